@@ -0,0 +1,239 @@
+use anyhow::Result;
+use log::error;
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::commands::{self, CommandResult, State};
+use crate::config::{Config, DoorConfig};
+use crate::health;
+use crate::http::{Request, Response};
+use crate::locate::DeviceHandle;
+use crate::metrics;
+use crate::poller::door_open;
+use crate::retry::retry;
+
+/// Command received on the REST API. It's handled by the main loop like a command from MQTT,
+/// so the state is published and the door relocks as usual.
+pub struct ApiCommand {
+    pub command_topic: String,
+    pub payload: Vec<u8>,
+    // Outcome of the command, `None` if it was ignored
+    pub reply: oneshot::Sender<Option<CommandResult>>,
+}
+
+/// State of a door as returned by `GET /door/{n}/status`.
+#[derive(Serialize)]
+struct DoorStatus {
+    door: u8,
+    name: String,
+    state: Option<State>,
+    mode: Option<&'static str>,
+    delay: u64,
+    // Only known for doors with a sensor
+    #[serde(skip_serializing_if = "Option::is_none")]
+    open: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct ApiError {
+    error: String,
+}
+
+fn error(status: &'static str, message: String) -> Response {
+    Response::json(
+        status,
+        serde_json::to_string(&ApiError { error: message }).unwrap(),
+    )
+}
+
+/// REST API to send commands to the doors and read their state. Doors are addressed as
+/// `/door/{n}`, or as `/device/{id}/door/{n}` when several devices have a door `n`.
+pub struct Api {
+    config: Arc<Config>,
+    devices: Vec<Arc<DeviceHandle>>,
+    commands: mpsc::Sender<ApiCommand>,
+}
+
+impl Api {
+    pub fn new(
+        config: Arc<Config>,
+        devices: Vec<Arc<DeviceHandle>>,
+        commands: mpsc::Sender<ApiCommand>,
+    ) -> Self {
+        Api {
+            config,
+            devices,
+            commands,
+        }
+    }
+
+    /// Answer `request`, or return `None` if its path isn't part of the API.
+    pub async fn handle(&self, request: Request) -> Option<Response> {
+        let segments = request
+            .path
+            .trim_matches('/')
+            .split('/')
+            .collect::<Vec<_>>();
+        let (device_id, door, endpoint) = match segments.as_slice() {
+            ["door", door, endpoint] => (None, *door, *endpoint),
+            ["device", device_id, "door", door, endpoint] => (Some(*device_id), *door, *endpoint),
+            _ => return None,
+        };
+        if endpoint != "command" && endpoint != "status" {
+            return None;
+        }
+
+        if !self.authorized(&request) {
+            return Some(error(
+                "401 Unauthorized",
+                "Missing or invalid token".to_string(),
+            ));
+        }
+
+        let (device, door) = match self.find_door(device_id, door) {
+            Ok(target) => target,
+            Err(response) => return Some(response),
+        };
+
+        Some(match (request.method.as_str(), endpoint) {
+            ("POST", "command") => self.command(door, request.body).await,
+            ("GET", "status") => status(device, door.clone()).await,
+            (method, endpoint) => error(
+                "405 Method Not Allowed",
+                format!("{} isn't supported for /{}", method, endpoint),
+            ),
+        })
+    }
+
+    /// Whether `request` carries the configured token, if any.
+    fn authorized(&self, request: &Request) -> bool {
+        let token = match &self.config.http_api_token {
+            Some(token) => token,
+            None => return true,
+        };
+        match request
+            .authorization
+            .as_deref()
+            .and_then(|authorization| authorization.strip_prefix("Bearer "))
+        {
+            Some(given) => constant_time_eq(given.trim().as_bytes(), token.as_bytes()),
+            None => false,
+        }
+    }
+
+    /// Find door `door` of the device with `device_id`, or of any device if it's omitted.
+    fn find_door(
+        &self,
+        device_id: Option<&str>,
+        door: &str,
+    ) -> Result<(Arc<DeviceHandle>, &DoorConfig), Response> {
+        let not_found = || error("404 Not Found", format!("Door {} isn't configured", door));
+
+        let number = door.parse::<u8>().map_err(|_| not_found())?;
+        let device_id = match device_id {
+            Some(id) => Some(id.parse::<u32>().map_err(|_| not_found())?),
+            None => None,
+        };
+
+        let mut matches = self
+            .config
+            .devices
+            .iter()
+            .zip(&self.devices)
+            .filter(|(device_config, _)| {
+                device_id.is_none() || device_id == Some(device_config.uhppote_device_id)
+            })
+            .flat_map(|(device_config, device)| {
+                device_config
+                    .doors
+                    .iter()
+                    .filter(|d| d.door == number)
+                    .map(move |d| (device.clone(), d))
+            });
+
+        match (matches.next(), matches.next()) {
+            (Some(target), None) => Ok(target),
+            (Some(_), Some(_)) => Err(error(
+                "400 Bad Request",
+                format!(
+                    "Door {} exists on several devices, use /device/{{id}}/door/{}",
+                    number, number
+                ),
+            )),
+            (None, _) => Err(not_found()),
+        }
+    }
+
+    /// Send the command in `payload` to `door` through the main loop and wait for the outcome.
+    async fn command(&self, door: &DoorConfig, payload: Vec<u8>) -> Response {
+        if let Err(e) = commands::validate(&payload, &self.config.aliases) {
+            return error("400 Bad Request", e.to_string());
+        }
+
+        let (reply, outcome) = oneshot::channel();
+        let command = ApiCommand {
+            command_topic: door.command_topic(),
+            payload,
+            reply,
+        };
+        if self.commands.send(command).await.is_err() {
+            return error("503 Service Unavailable", "Shutting down".to_string());
+        }
+
+        match outcome.await {
+            Ok(Some(result)) => Response::json(
+                // Valid commands only fail when the device doesn't cooperate
+                if result.success {
+                    "200 OK"
+                } else {
+                    "502 Bad Gateway"
+                },
+                serde_json::to_string(&result).unwrap(),
+            ),
+            Ok(None) => error("400 Bad Request", "Command was ignored".to_string()),
+            Err(_) => error(
+                "500 Internal Server Error",
+                "Command wasn't handled".to_string(),
+            ),
+        }
+    }
+}
+
+/// Read the state of `door` from `device`, like the state poller does.
+async fn status(device: Arc<DeviceHandle>, door: DoorConfig) -> Response {
+    let number = door.door;
+    let result = tokio::task::spawn_blocking(move || -> Result<_> {
+        let control = retry(|| device.get_door_control(number))?;
+        let status = retry(|| device.get_status())?;
+        Ok((control, status))
+    })
+    .await
+    .unwrap();
+
+    match result {
+        Ok((control, status)) => {
+            health::LAST_DEVICE_RESPONSE.set_now();
+            let state = State::from_mode(&control.mode);
+            let door_status = DoorStatus {
+                door: door.door,
+                name: door.name.clone(),
+                state,
+                mode: state.and_then(|state| state.mode()),
+                delay: control.delay.as_secs(),
+                open: door_open(&door, &status),
+            };
+            Response::json("200 OK", serde_json::to_string(&door_status).unwrap())
+        }
+        Err(e) => {
+            metrics::DEVICE_ERRORS.inc();
+            error!("Failed to get state of door {}: {}", number, e);
+            error("502 Bad Gateway", e.to_string())
+        }
+    }
+}
+
+/// Compare `a` and `b` in constant time, so the token can't be guessed byte by byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
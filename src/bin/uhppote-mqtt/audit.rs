@@ -0,0 +1,250 @@
+use chrono::{SecondsFormat, Utc};
+use log::{error, info};
+use ring::digest;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::commands::CommandResult;
+use crate::config::DoorConfig;
+
+/// Lines for the audit log. Writing is left to a dedicated thread, so a slow disk doesn't hold
+/// up the MQTT loop.
+static AUDIT: OnceLock<UnboundedSender<String>> = OnceLock::new();
+
+/// Hash the first entry of a new audit log is chained to.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Bytes read from the end of an existing audit log to find its last entry.
+const TAIL_SIZE: u64 = 64 * 1024;
+
+/// Entry of the audit log. Every entry is a single JSON line, which also has the SHA-256 hash
+/// of the line before it in `prev`. Removing or changing an entry breaks the chain, though
+/// someone who can write the file can still rewrite all entries after it.
+#[derive(Serialize)]
+struct Entry<'a> {
+    timestamp: String,
+    // `command`, `request`, `state` or `event`
+    kind: &'static str,
+    // What sent a command: `mqtt`, `api`, `relock` or `all`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<&'static str>,
+    topic: &'a str,
+    payload: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    success: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    // `ignored`, `rejected` or `dropped` for a command that wasn't handled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outcome: Option<&'static str>,
+}
+
+/// Start writing the audit log to `path`. When it grows beyond `max_bytes`, it's moved to
+/// `<path>.1` and a new one is started. 0 lets it grow forever.
+pub fn start(path: PathBuf, max_bytes: u64) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    AUDIT.set(tx).expect("Audit log already started");
+    info!("Writing audit log to {:?}", path);
+    std::thread::spawn(move || write(&path, max_bytes, rx));
+}
+
+/// Record the command in `payload` for `door` received from `source` and its outcome, or that
+/// it was ignored.
+pub fn command(
+    source: &'static str,
+    door: &DoorConfig,
+    payload: &[u8],
+    result: Option<&CommandResult>,
+) {
+    let topic = door.command_topic();
+    let payload = String::from_utf8_lossy(payload);
+    record(match result {
+        Some(result) => Entry {
+            timestamp: now(),
+            kind: "command",
+            source: Some(source),
+            topic: &topic,
+            payload: &result.command,
+            success: Some(result.success),
+            error: result.error.as_deref(),
+            outcome: None,
+        },
+        None => Entry {
+            timestamp: now(),
+            kind: "command",
+            source: Some(source),
+            topic: &topic,
+            payload: &payload,
+            success: None,
+            error: None,
+            outcome: Some("ignored"),
+        },
+    });
+}
+
+/// Record a message received on `topic` that was rejected before it was handled, like one
+/// without a valid signature.
+pub fn rejected(topic: &str, payload: &[u8], error: &str) {
+    record(Entry {
+        timestamp: now(),
+        kind: "command",
+        source: Some("mqtt"),
+        topic,
+        payload: &String::from_utf8_lossy(payload),
+        success: Some(false),
+        error: Some(error),
+        outcome: Some("rejected"),
+    });
+}
+
+/// Record a command received on `topic` that was held back and replaced by a newer one before
+/// it was handled.
+pub fn dropped(topic: &str, payload: &[u8]) {
+    record(Entry {
+        timestamp: now(),
+        kind: "command",
+        source: Some("mqtt"),
+        topic,
+        payload: &String::from_utf8_lossy(payload),
+        success: None,
+        error: None,
+        outcome: Some("dropped"),
+    });
+}
+
+/// Record a request received on a device `topic`, like adding a card, with the `response`
+/// published to its result topic.
+pub fn request(topic: &str, payload: &[u8], response: &str) {
+    let response: serde_json::Value = serde_json::from_str(response).unwrap_or_default();
+    let error = response.get("error").and_then(|error| error.as_str());
+    let success = response
+        .get("success")
+        .and_then(|success| success.as_bool())
+        .unwrap_or(error.is_none());
+    record(Entry {
+        timestamp: now(),
+        kind: "request",
+        source: Some("mqtt"),
+        topic,
+        payload: &String::from_utf8_lossy(payload),
+        success: Some(success),
+        error,
+        outcome: None,
+    });
+}
+
+/// Record a state published to `topic`.
+pub fn state(topic: &str, payload: &str) {
+    record(Entry {
+        timestamp: now(),
+        kind: "state",
+        source: None,
+        topic,
+        payload,
+        success: None,
+        error: None,
+        outcome: None,
+    });
+}
+
+/// Record an access event published to `topic`.
+pub fn event(topic: &str, payload: &str) {
+    record(Entry {
+        timestamp: now(),
+        kind: "event",
+        source: None,
+        topic,
+        payload,
+        success: None,
+        error: None,
+        outcome: None,
+    });
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn record(entry: Entry) {
+    if let Some(audit) = AUDIT.get() {
+        audit.send(serde_json::to_string(&entry).unwrap()).ok();
+    }
+}
+
+/// Append the lines from `rx` to the audit log at `path` until the channel closes. The chain
+/// of hashes continues from the last entry already in the log, also after rotating it.
+fn write(path: &Path, max_bytes: u64, mut rx: UnboundedReceiver<String>) {
+    let mut file = open(path);
+    let mut prev = last_hash(path);
+    while let Some(line) = rx.blocking_recv() {
+        // Entries are JSON objects, so the hash goes in before the closing brace
+        let line = format!("{},\"prev\":\"{}\"}}", &line[..line.len() - 1], prev);
+        prev = hash(line.as_bytes());
+
+        if let Some(f) = &file {
+            let size = f.metadata().map(|m| m.len()).unwrap_or(0);
+            if max_bytes > 0 && size > 0 && size + line.len() as u64 + 1 > max_bytes {
+                file = None;
+                let rotated = PathBuf::from(format!("{}.1", path.display()));
+                if let Err(e) = std::fs::rename(path, &rotated) {
+                    error!("Failed to rotate audit log {:?}: {}", path, e);
+                }
+            }
+        }
+        // Reopen after rotating, or when the file couldn't be opened before
+        if file.is_none() {
+            file = open(path);
+        }
+
+        if let Some(f) = &mut file {
+            if let Err(e) = writeln!(f, "{}", line) {
+                error!("Failed to write audit log {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// Hash of the last entry in the audit log at `path`, or [`GENESIS`] when it's empty or doesn't
+/// exist.
+fn last_hash(path: &Path) -> String {
+    let mut tail = Vec::new();
+    let read = File::open(path).and_then(|mut file| {
+        let len = file.metadata()?.len();
+        file.seek(SeekFrom::Start(len.saturating_sub(TAIL_SIZE)))?;
+        file.read_to_end(&mut tail)
+    });
+    if read.is_err() {
+        return GENESIS.to_string();
+    }
+    match tail
+        .strip_suffix(b"\n")
+        .unwrap_or(&tail)
+        .rsplit(|b| *b == b'\n')
+        .next()
+    {
+        Some(line) if !line.is_empty() => hash(line),
+        _ => GENESIS.to_string(),
+    }
+}
+
+fn hash(line: &[u8]) -> String {
+    digest::digest(&digest::SHA256, line)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn open(path: &Path) -> Option<File> {
+    match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => Some(file),
+        Err(e) => {
+            error!("Failed to open audit log {:?}: {}", path, e);
+            None
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use anyhow::Result;
+use chrono::{Duration as ChronoDuration, Timelike};
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use uhppote_rs::{Task, TaskID};
+
+use crate::commands::{State, Transition, UnknownCommand};
+use crate::locate::DeviceHandle;
+use crate::retry::retry;
+
+/// Last state the exit button of each door was set to, by device id and door.
+///
+/// The controller has no request to read whether a button is enabled, it can only be changed
+/// with a task. So this only knows about the changes made since the bridge started, and not
+/// about those made with other tools.
+static ENABLED: Mutex<BTreeMap<(u32, u8), bool>> = Mutex::new(BTreeMap::new());
+
+/// Enable or disable the exit button of `door` with the `ENABLE` or `DISABLE` command in
+/// `payload`, and return the state the door is in.
+///
+/// The controller only changes the button with a task, which runs at the start of the next
+/// minute on its clock. Until then, the button keeps working like before.
+pub fn handle(
+    device: &DeviceHandle,
+    door: u8,
+    payload: &[u8],
+    dry_run: bool,
+) -> Result<Option<Transition>> {
+    let payload = match std::str::from_utf8(payload) {
+        Ok(payload) => payload,
+        Err(_) => {
+            warn!("Ignoring button command that isn't valid UTF-8");
+            return Ok(None);
+        }
+    };
+    let enable = match payload.trim().to_uppercase().as_str() {
+        "ENABLE" => true,
+        "DISABLE" => false,
+        _ => return Err(UnknownCommand(payload.to_string()).into()),
+    };
+    let action = if enable { "Enabling" } else { "Disabling" };
+
+    if dry_run {
+        info!(
+            "Dry run: not {} the exit button of door {}",
+            action.to_lowercase(),
+            door
+        );
+    } else {
+        // Tasks run at a time of day in minutes, so the earliest is the start of the next one
+        let now = retry(|| device.get_time())?;
+        let at = now.with_second(0).unwrap() + ChronoDuration::minutes(1);
+        info!("{} the exit button of door {} at {}", action, door, at);
+        retry(|| {
+            device.add_task(Task {
+                task: if enable {
+                    TaskID::EnablePushButton
+                } else {
+                    TaskID::DisablePushButton
+                },
+                door,
+                from: at.date(),
+                to: at.date(),
+                monday: true,
+                tuesday: true,
+                wednesday: true,
+                thursday: true,
+                friday: true,
+                saturday: true,
+                sunday: true,
+                at: at.time(),
+                more_cards: 0,
+            })
+        })?;
+        // Added tasks only take effect once the task list is refreshed
+        retry(|| device.refresh_task_list())?;
+        ENABLED.lock().unwrap().insert((device.id(), door), enable);
+    }
+
+    let control = retry(|| device.get_door_control(door))?;
+    Ok(State::from_mode(&control.mode).map(|state| Transition {
+        state,
+        delay: control.delay,
+        after: None,
+    }))
+}
+
+/// Payload for the button state topic of `door` of the device with `device_id`, or `None` when
+/// the button wasn't set since the bridge started.
+pub fn payload(device_id: u32, door: u8) -> Option<&'static str> {
+    ENABLED
+        .lock()
+        .unwrap()
+        .get(&(device_id, door))
+        .map(|enabled| if *enabled { "ENABLED" } else { "DISABLED" })
+}
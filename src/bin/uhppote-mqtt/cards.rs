@@ -0,0 +1,289 @@
+use anyhow::{bail, Context, Result};
+use chrono::NaiveDate;
+use log::{debug, error, info};
+use rumqttc::AsyncClient;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use uhppote_rs::{Card, Device};
+
+use crate::config::{Config, DeviceConfig, DoorConfig};
+use crate::health;
+use crate::locate::DeviceHandle;
+use crate::metrics;
+use crate::retry::retry;
+
+/// Request on the card set topic, like
+/// `{"card":12345,"from":"2024-01-01","to":"2024-12-31","doors":[1,2]}`.
+#[derive(Deserialize)]
+struct SetCard {
+    card: u32,
+    from: String,
+    to: String,
+    doors: Vec<u8>,
+}
+
+/// Request on the card delete topic. Either a plain card number or `{"card":12345}`.
+#[derive(Deserialize)]
+struct DeleteCard {
+    card: u32,
+}
+
+/// Request on the card privilege topic, like `{"card":12345,"door":1,"profile":2}`. Limits the
+/// access of the card to `door` to the times in time profile `profile`.
+#[derive(Deserialize)]
+struct SetPrivilege {
+    card: u32,
+    door: u8,
+    profile: u8,
+}
+
+/// Outcome of a card request as published to the card result topic.
+#[derive(Serialize)]
+pub struct CardResult {
+    action: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    card: Option<u32>,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Part of the card list as published to the card list topic. A controller can hold thousands
+/// of cards, so they're published in chunks.
+#[derive(Serialize)]
+pub struct CardList {
+    // Position of the first card of this chunk in the complete list
+    offset: usize,
+    total: usize,
+    cards: Vec<ListedCard>,
+}
+
+/// Card in the same format as a card set request, so a list can be restored.
+#[derive(Serialize, Clone)]
+struct ListedCard {
+    card: u32,
+    from: String,
+    to: String,
+    doors: Vec<u8>,
+}
+
+/// Maximum number of cards in a single card list message, keeps the payload below ~10kB.
+const CARDS_PER_CHUNK: usize = 100;
+
+impl CardResult {
+    pub fn new(action: &'static str, card: Option<u32>, result: Result<()>) -> Self {
+        CardResult {
+            action,
+            card,
+            success: result.is_ok(),
+            error: result.err().map(|e| e.to_string()),
+        }
+    }
+}
+
+/// Add a card, or update it when it already exists.
+pub fn handle_set(device: &Device, payload: &[u8]) -> CardResult {
+    match serde_json::from_slice::<SetCard>(payload) {
+        Ok(request) => {
+            let card = request.card;
+            CardResult::new("set", Some(card), set_card(device, request))
+        }
+        Err(e) => CardResult::new("set", None, Err(e.into())),
+    }
+}
+
+/// Remove a card.
+pub fn handle_delete(device: &Device, payload: &[u8]) -> CardResult {
+    let payload = String::from_utf8_lossy(payload);
+    let card = match payload.trim().parse() {
+        Ok(card) => Ok(card),
+        Err(_) => serde_json::from_str::<DeleteCard>(&payload).map(|request| request.card),
+    };
+
+    match card {
+        Ok(card) => {
+            info!("Deleting card {}", card);
+            let result =
+                retry(|| device.delete_card(card)).inspect_err(|_| metrics::DEVICE_ERRORS.inc());
+            CardResult::new("delete", Some(card), result)
+        }
+        Err(e) => CardResult::new("delete", None, Err(e.into())),
+    }
+}
+
+/// Limit the access of an existing card to a door to a time profile. `doors` are the doors
+/// configured for the device.
+pub fn handle_privilege(device: &Device, doors: &[DoorConfig], payload: &[u8]) -> CardResult {
+    match serde_json::from_slice::<SetPrivilege>(payload) {
+        Ok(request) => {
+            let card = request.card;
+            CardResult::new(
+                "privilege",
+                Some(card),
+                set_privilege(device, doors, request),
+            )
+        }
+        Err(e) => CardResult::new("privilege", None, Err(e.into())),
+    }
+}
+
+fn set_privilege(device: &Device, doors: &[DoorConfig], request: SetPrivilege) -> Result<()> {
+    if !doors.iter().any(|door| door.door == request.door) {
+        bail!("Door {} is not configured", request.door);
+    }
+
+    // 0 and 1 mean no access and access at any time, profiles are numbered from 2
+    if !(2..=254).contains(&request.profile) {
+        bail!(
+            "Invalid time profile {}, it must be between 2 and 254",
+            request.profile
+        );
+    }
+
+    let card = retry(|| device.get_card_by_id(request.card))
+        .inspect_err(|_| metrics::DEVICE_ERRORS.inc())?;
+    if card.number != request.card {
+        bail!("Card {} does not exist", request.card);
+    }
+
+    info!(
+        "Limiting card {} on door {} to time profile {}",
+        request.card, request.door, request.profile
+    );
+    let mut doors = card.doors;
+    doors[request.door as usize - 1] = request.profile;
+    retry(|| {
+        device.add_card(Card {
+            number: card.number,
+            from: card.from,
+            to: card.to,
+            doors: doors.clone(),
+        })
+    })
+    .inspect_err(|_| metrics::DEVICE_ERRORS.inc())
+}
+
+fn set_card(device: &Device, request: SetCard) -> Result<()> {
+    if request.card == 0 {
+        bail!("Card number must not be 0");
+    }
+
+    let from = parse_date(&request.from)?;
+    let to = parse_date(&request.to)?;
+    if from > to {
+        bail!("Card is valid from {} which is after {}", from, to);
+    }
+
+    if request.doors.is_empty() {
+        bail!("No doors specified");
+    }
+
+    // The controller takes a permission for each of its four doors
+    let mut doors = vec![0; 4];
+    for door in request.doors {
+        if !(1..=4).contains(&door) {
+            bail!("Invalid door {}, it must be between 1 and 4", door);
+        }
+        doors[door as usize - 1] = 1;
+    }
+
+    info!("Setting card {}", request.card);
+    retry(|| {
+        device.add_card(Card {
+            number: request.card,
+            from,
+            to,
+            doors: doors.clone(),
+        })
+    })
+    .inspect_err(|_| metrics::DEVICE_ERRORS.inc())
+}
+
+/// Read all cards from the device and split them into chunks. There's always at least one
+/// chunk, so an empty list is also published.
+pub fn list(device: &Device) -> Result<Vec<CardList>> {
+    let records = retry(|| device.get_cards())?;
+    info!("Reading {} card records", records);
+
+    let mut cards = Vec::new();
+    for index in 1..=records {
+        let card = retry(|| device.get_card_by_index(index))?;
+        // Records of deleted cards stay around until the controller compacts its list
+        if card.number == 0 || card.number == u32::MAX {
+            continue;
+        }
+        cards.push(ListedCard {
+            card: card.number,
+            from: card.from.format("%Y-%m-%d").to_string(),
+            to: card.to.format("%Y-%m-%d").to_string(),
+            doors: (1..=4)
+                .zip(&card.doors)
+                .filter(|(_, permission)| **permission != 0)
+                .map(|(door, _)| door)
+                .collect(),
+        });
+    }
+
+    let total = cards.len();
+    if cards.is_empty() {
+        return Ok(vec![CardList {
+            offset: 0,
+            total,
+            cards,
+        }]);
+    }
+    Ok(cards
+        .chunks(CARDS_PER_CHUNK)
+        .enumerate()
+        .map(|(i, chunk)| CardList {
+            offset: i * CARDS_PER_CHUNK,
+            total,
+            cards: chunk.to_vec(),
+        })
+        .collect())
+}
+
+fn parse_date(date: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .with_context(|| format!("Invalid date '{}', expected YYYY-MM-DD", date))
+}
+
+/// Periodically read the number of cards stored on the device and publish it. The controller
+/// also counts records of deleted cards until it compacts its list.
+pub async fn monitor_count(
+    client: AsyncClient,
+    device: Arc<DeviceHandle>,
+    config: Arc<Config>,
+    device_config: DeviceConfig,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.card_count_poll_interval));
+    loop {
+        interval.tick().await;
+        let device = device.clone();
+        let count = match tokio::task::spawn_blocking(move || retry(|| device.get_cards()))
+            .await
+            .unwrap()
+        {
+            Ok(count) => count,
+            Err(e) => {
+                metrics::DEVICE_ERRORS.inc();
+                error!("Failed to get card count: {}", e);
+                continue;
+            }
+        };
+        health::LAST_DEVICE_RESPONSE.set_now();
+
+        let topic = device_config.card_count_topic();
+        debug!("Publishing {} to {}", count, &topic);
+        client
+            .publish(
+                &topic,
+                config.state_qos,
+                config.retain_state,
+                count.to_string(),
+            )
+            .await
+            .unwrap();
+    }
+}
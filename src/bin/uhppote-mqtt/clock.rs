@@ -0,0 +1,141 @@
+use anyhow::Result;
+use chrono::{Local, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use log::{debug, error, info, warn};
+use rumqttc::AsyncClient;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use uhppote_rs::Device;
+
+use crate::config::{Config, DeviceConfig};
+use crate::health;
+use crate::locate::DeviceHandle;
+use crate::metrics;
+use crate::retry::retry;
+
+/// Drift of a device clock as published to the clock drift topic.
+#[derive(Serialize)]
+struct DriftPayload {
+    // Positive when the device clock is ahead
+    drift_seconds: i64,
+    // Whether the drift exceeds the threshold, `ON` or `OFF`
+    problem: &'static str,
+}
+
+/// Current local time in `timezone`, or in the host's timezone when it's not specified.
+fn now(timezone: Option<Tz>) -> NaiveDateTime {
+    match timezone {
+        Some(tz) => Utc::now().with_timezone(&tz).naive_local(),
+        None => Local::now().naive_local(),
+    }
+}
+
+/// Set the device's clock to the current local time.
+pub fn sync_time(device: &Device, timezone: Option<Tz>) -> Result<()> {
+    let old = retry(|| device.get_time())?;
+    let new = now(timezone);
+    info!(
+        "Setting device time to {} (was {}, drift {}s)",
+        new,
+        old,
+        (old - new).num_seconds()
+    );
+    retry(|| device.set_time(new))?;
+    Ok(())
+}
+
+/// Periodically set the device's clock to the current local time.
+pub async fn sync_periodically(
+    device: Arc<DeviceHandle>,
+    timezone: Option<Tz>,
+    interval: Duration,
+) {
+    let mut interval = tokio::time::interval(interval);
+    // The first tick completes immediately and the clock was just set on startup
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let device = device.clone();
+        let result = tokio::task::spawn_blocking(move || sync_time(&device, timezone))
+            .await
+            .unwrap();
+        if let Err(e) = result {
+            metrics::DEVICE_ERRORS.inc();
+            error!("Failed to sync device time: {}", e);
+        }
+    }
+}
+
+/// Periodically read the device's clock and publish its time and how far it's off from the
+/// host's clock, so drift can be noticed before it corrupts event timestamps.
+pub async fn monitor_drift(
+    client: AsyncClient,
+    device: Arc<DeviceHandle>,
+    config: Arc<Config>,
+    device_config: DeviceConfig,
+) {
+    let threshold = config.clock_drift_threshold as i64;
+    let mut interval = tokio::time::interval(Duration::from_secs(config.clock_poll_interval));
+    loop {
+        interval.tick().await;
+        let device = device.clone();
+        let time = match tokio::task::spawn_blocking(move || retry(|| device.get_time()))
+            .await
+            .unwrap()
+        {
+            Ok(time) => time,
+            Err(e) => {
+                metrics::DEVICE_ERRORS.inc();
+                error!("Failed to get device time: {}", e);
+                continue;
+            }
+        };
+        health::LAST_DEVICE_RESPONSE.set_now();
+
+        let drift = (time - now(config.timezone)).num_seconds();
+        if drift.abs() > threshold {
+            warn!(
+                "Clock of device {} is off by {}s, more than the threshold of {}s",
+                device_config.uhppote_device_id, drift, threshold
+            );
+        }
+
+        let time_topic = device_config.controller_time_topic();
+        let payload = to_rfc3339(time, config.timezone);
+        debug!("Publishing {} to {}", &payload, &time_topic);
+        client
+            .publish(&time_topic, config.state_qos, config.retain_state, payload)
+            .await
+            .unwrap();
+
+        let drift_topic = device_config.clock_drift_topic();
+        let payload = serde_json::to_string(&DriftPayload {
+            drift_seconds: drift,
+            problem: if drift.abs() > threshold { "ON" } else { "OFF" },
+        })
+        .unwrap();
+        debug!("Publishing {} to {}", &payload, &drift_topic);
+        client
+            .publish(&drift_topic, config.state_qos, config.retain_state, payload)
+            .await
+            .unwrap();
+    }
+}
+
+/// Device clocks have no timezone, so `time` is taken to be in `timezone`, or in the host's
+/// timezone when it's not specified, like when the clock is set.
+fn to_rfc3339(time: NaiveDateTime, timezone: Option<Tz>) -> String {
+    let converted = match timezone {
+        Some(tz) => tz
+            .from_local_datetime(&time)
+            .earliest()
+            .map(|t| t.to_rfc3339()),
+        None => Local
+            .from_local_datetime(&time)
+            .earliest()
+            .map(|t| t.to_rfc3339()),
+    };
+    // Times skipped by a DST change have no offset
+    converted.unwrap_or_else(|| time.format("%Y-%m-%dT%H:%M:%S").to_string())
+}
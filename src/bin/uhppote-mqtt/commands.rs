@@ -0,0 +1,444 @@
+use anyhow::{bail, Result};
+use log::{info, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+use uhppote_rs::{Device, DoorControl, DoorControlMode};
+
+use crate::config::{CommandAlias, DoorConfig};
+use crate::metrics;
+use crate::retry::retry;
+
+/// State of a door as published to the state topic.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum State {
+    Locked,
+    Unlocked,
+    Open,
+    // Normally closed: locked, and cards don't unlock the door either
+    Secured,
+}
+
+impl State {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            State::Locked => "LOCKED",
+            State::Unlocked => "UNLOCKED",
+            State::Open => "OPEN",
+            State::Secured => "SECURED",
+        }
+    }
+
+    /// Door control mode the door is in, named like in JSON commands. An open door can be in
+    /// any mode.
+    pub fn mode(&self) -> Option<&'static str> {
+        match self {
+            State::Locked => Some("controlled"),
+            State::Unlocked => Some("normally_open"),
+            State::Secured => Some("normally_closed"),
+            State::Open => None,
+        }
+    }
+
+    pub fn from_mode(mode: &DoorControlMode) -> Option<State> {
+        match mode {
+            DoorControlMode::Controlled => Some(State::Locked),
+            DoorControlMode::NormallyOpen => Some(State::Unlocked),
+            DoorControlMode::NormallyClosed => Some(State::Secured),
+            DoorControlMode::Unknown => None,
+        }
+    }
+}
+
+/// [`DoorControlMode`] isn't `Clone`, but a request needs its own copy every time it's sent.
+pub fn copy_mode(mode: &DoorControlMode) -> DoorControlMode {
+    match mode {
+        DoorControlMode::Controlled => DoorControlMode::Controlled,
+        DoorControlMode::NormallyOpen => DoorControlMode::NormallyOpen,
+        DoorControlMode::NormallyClosed => DoorControlMode::NormallyClosed,
+        DoorControlMode::Unknown => DoorControlMode::Unknown,
+    }
+}
+
+/// Result of a command: the new state, the delay the door was set to and, for momentary
+/// commands, the state the door returns to after the delay.
+pub struct Transition {
+    pub state: State,
+    pub delay: Duration,
+    pub after: Option<State>,
+}
+
+/// Outcome of a command as published to the result topic.
+#[derive(Serialize)]
+pub struct CommandResult {
+    pub command: String,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<State>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Outcome of a command for all doors as published to the all doors result topic.
+#[derive(Serialize)]
+pub struct AllCommandResult {
+    pub command: String,
+    // Whether the command succeeded for every door
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub doors: Vec<DoorResult>,
+}
+
+/// Outcome of a command for all doors for one of them.
+#[derive(Serialize)]
+pub struct DoorResult {
+    pub device: u32,
+    pub door: u8,
+    pub name: String,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Command for all doors at once, for emergencies.
+#[derive(Clone, Copy)]
+pub enum Emergency {
+    // Secure every door, so cards don't open them either
+    Lockdown,
+    // Unlock every door, so everyone can get out
+    Evacuate,
+}
+
+impl Emergency {
+    pub fn parse(payload: &str) -> Option<Emergency> {
+        match payload.trim().to_uppercase().as_str() {
+            "LOCKDOWN" => Some(Emergency::Lockdown),
+            "EVACUATE" => Some(Emergency::Evacuate),
+            _ => None,
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Emergency::Lockdown => "Locking down",
+            Emergency::Evacuate => "Evacuating, unlocking",
+        }
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Lock,
+    Unlock,
+    Open,
+    Secure,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Mode {
+    Controlled,
+    NormallyOpen,
+    NormallyClosed,
+}
+
+impl From<Mode> for DoorControlMode {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::Controlled => DoorControlMode::Controlled,
+            Mode::NormallyOpen => DoorControlMode::NormallyOpen,
+            Mode::NormallyClosed => DoorControlMode::NormallyClosed,
+        }
+    }
+}
+
+/// Command received on the command topic. Either a plain string like `UNLOCK`, or a JSON
+/// object like `{"action":"unlock","delay":10,"mode":"normally_open"}`.
+#[derive(Deserialize, Debug)]
+struct Command {
+    action: Action,
+    delay: Option<u32>,
+    mode: Option<Mode>,
+}
+
+/// Plain payloads accepted on the command topic. `ON` and `OFF` are what switch entities send.
+const DEFAULT_ALIASES: [(&str, Action); 8] = [
+    ("LOCK", Action::Lock),
+    ("ON", Action::Lock),
+    ("CLOSE", Action::Lock),
+    ("UNLOCK", Action::Unlock),
+    ("OFF", Action::Unlock),
+    ("OPEN_PERM", Action::Unlock),
+    ("OPEN", Action::Open),
+    ("SECURE", Action::Secure),
+];
+
+/// All plain payloads accepted on the command topic, keyed in uppercase. The `extra` aliases
+/// are added to the defaults and take precedence over them.
+pub fn aliases(extra: &[CommandAlias]) -> HashMap<String, Action> {
+    DEFAULT_ALIASES
+        .iter()
+        .map(|(payload, action)| (payload.to_string(), *action))
+        .chain(
+            extra
+                .iter()
+                .map(|alias| (alias.payload.to_uppercase(), alias.action)),
+        )
+        .collect()
+}
+
+impl Command {
+    fn parse(payload: &str, aliases: &HashMap<String, Action>) -> Result<Command> {
+        let trimmed = payload.trim();
+        if trimmed.starts_with('{') {
+            let command: Command = serde_json::from_str(trimmed)?;
+            // The controller only supports delays of 1 to 254 seconds
+            if let Some(d) = command.delay.filter(|d| !(1..=254).contains(d)) {
+                bail!("Delay must be between 1 and 254 seconds, got {}", d)
+            }
+            return Ok(command);
+        }
+        let action = match aliases.get(&trimmed.to_uppercase()) {
+            Some(action) => *action,
+            None => return Err(UnknownCommand(payload.to_string()).into()),
+        };
+
+        Ok(Command {
+            action,
+            delay: None,
+            mode: None,
+        })
+    }
+}
+
+/// A plain payload that isn't one of the accepted commands.
+#[derive(Debug)]
+pub struct UnknownCommand(pub String);
+
+impl fmt::Display for UnknownCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown command '{}'", self.0)
+    }
+}
+
+impl std::error::Error for UnknownCommand {}
+
+/// Check that `payload` is a valid command, without sending it.
+pub fn validate(payload: &[u8], aliases: &HashMap<String, Action>) -> Result<()> {
+    Command::parse(std::str::from_utf8(payload)?, aliases).map(|_| ())
+}
+
+/// Send the command in `payload` to `door` of `device`. With `dry_run`, nothing is sent and the
+/// state the door would end up in is returned.
+pub fn handle_payload(
+    device: &Device,
+    door_config: &DoorConfig,
+    delay: Duration,
+    enforce_delay: bool,
+    payload: &[u8],
+    aliases: &HashMap<String, Action>,
+    dry_run: bool,
+) -> Result<Option<Transition>> {
+    // Garbage from a misbehaving publisher shouldn't end up in the result topic, so just log it
+    let payload = match std::str::from_utf8(payload) {
+        Ok(payload) => payload,
+        Err(_) => {
+            let hex: String = payload.iter().map(|b| format!("{:02x}", b)).collect();
+            warn!("Ignoring command that isn't valid UTF-8: {}", hex);
+            return Ok(None);
+        }
+    };
+    let command = Command::parse(payload, aliases)?;
+
+    // A delay given with the command is set even when it isn't enforced
+    let enforce_delay = enforce_delay || command.delay.is_some();
+    let delay = command
+        .delay
+        .map_or(delay, |d| Duration::from_secs(d.into()));
+
+    let door = door_config.door;
+    let unlocks = matches!(
+        (command.action, &command.mode),
+        (Action::Open, _)
+            | (Action::Unlock, None)
+            | (Action::Lock | Action::Unlock, Some(Mode::NormallyOpen))
+    );
+    if unlocks {
+        check_interlock(device, door_config)?;
+    }
+
+    let result = match command.action {
+        Action::Lock => {
+            info!("Locking");
+            metrics::COMMANDS_LOCK.inc();
+            let mode = command.mode.map_or(DoorControlMode::Controlled, Into::into);
+            set_door_control_state(device, door, mode, delay, dry_run)
+        }
+        Action::Unlock => {
+            info!("Unlocking");
+            metrics::COMMANDS_UNLOCK.inc();
+            let mode = command
+                .mode
+                .map_or(DoorControlMode::NormallyOpen, Into::into);
+            set_door_control_state(device, door, mode, delay, dry_run)
+        }
+        Action::Open => {
+            info!("Opening");
+            metrics::COMMANDS_OPEN.inc();
+            open_door(device, door, delay, enforce_delay, dry_run)
+        }
+        Action::Secure => {
+            info!("Securing");
+            metrics::COMMANDS_SECURE.inc();
+            set_door_control_state(
+                device,
+                door,
+                DoorControlMode::NormallyClosed,
+                delay,
+                dry_run,
+            )
+        }
+    };
+
+    if result.is_err() {
+        metrics::DEVICE_ERRORS.inc();
+    }
+    result
+}
+
+/// Set `door_config` of `device` to the mode for `emergency`. Interlocks don't apply, since
+/// everyone must be able to get out during an evacuation.
+pub fn handle_emergency(
+    device: &Device,
+    door_config: &DoorConfig,
+    delay: Duration,
+    emergency: Emergency,
+    dry_run: bool,
+) -> Result<Option<Transition>> {
+    let mode = match emergency {
+        Emergency::Lockdown => {
+            metrics::COMMANDS_SECURE.inc();
+            DoorControlMode::NormallyClosed
+        }
+        Emergency::Evacuate => {
+            metrics::COMMANDS_UNLOCK.inc();
+            DoorControlMode::NormallyOpen
+        }
+    };
+    let result = set_door_control_state(device, door_config.door, mode, delay, dry_run);
+    if result.is_err() {
+        metrics::DEVICE_ERRORS.inc();
+    }
+    result
+}
+
+/// Fail when one of the doors interlocked with `door` is open.
+fn check_interlock(device: &Device, door: &DoorConfig) -> Result<()> {
+    if door.interlocked.is_empty() {
+        return Ok(());
+    }
+
+    let status = match retry(|| device.get_status()) {
+        Ok(status) => status,
+        Err(e) => {
+            metrics::DEVICE_ERRORS.inc();
+            return Err(e);
+        }
+    };
+    let open = door
+        .interlocked
+        .iter()
+        .filter(|other| status.doors.get(**other as usize - 1) == Some(&true))
+        .map(|other| other.to_string())
+        .collect::<Vec<_>>();
+    if !open.is_empty() {
+        bail!(
+            "Not unlocking door {}, interlocked door {} is open",
+            door.door,
+            open.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Open `door` once. The controller keeps it open for its configured delay, which is only set
+/// to `delay` first with `enforce_delay`.
+fn open_door(
+    device: &Device,
+    door: u8,
+    delay: Duration,
+    enforce_delay: bool,
+    dry_run: bool,
+) -> Result<Option<Transition>> {
+    if dry_run {
+        info!("Dry run: not opening door {}", door);
+        return Ok(Some(Transition {
+            state: State::Open,
+            delay,
+            after: Some(State::Locked),
+        }));
+    }
+
+    let current = retry(|| device.get_door_control(door))?;
+    let after = State::from_mode(&current.mode);
+
+    // Changing the delay without touching the mode lasts beyond this open, so it's only done
+    // when asked for. Otherwise the door stays open for the delay it already has.
+    let delay = if enforce_delay { delay } else { current.delay };
+    if current.delay != delay {
+        retry(|| {
+            device.set_door_control_state(
+                door,
+                DoorControl {
+                    delay,
+                    mode: copy_mode(&current.mode),
+                },
+            )
+        })?;
+    }
+
+    retry(|| device.open_door(door))?;
+    Ok(Some(Transition {
+        state: State::Open,
+        delay,
+        after,
+    }))
+}
+
+fn set_door_control_state(
+    device: &Device,
+    door: u8,
+    mode: DoorControlMode,
+    delay: Duration,
+    dry_run: bool,
+) -> Result<Option<Transition>> {
+    let state = State::from_mode(&mode);
+    if dry_run {
+        info!(
+            "Dry run: not setting door {} to {:?} with a delay of {}s",
+            door,
+            mode,
+            delay.as_secs()
+        );
+    } else {
+        retry(|| {
+            device.set_door_control_state(
+                door,
+                DoorControl {
+                    delay,
+                    mode: copy_mode(&mode),
+                },
+            )
+        })?;
+    }
+    Ok(state.map(|state| Transition {
+        state,
+        delay,
+        after: None,
+    }))
+}
@@ -0,0 +1,1400 @@
+use anyhow::{bail, Context, Result};
+use chrono_tz::Tz;
+use rumqttc::QoS;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::commands::{self, Action, State};
+use crate::error::BridgeError;
+use crate::template;
+
+/// The controllers only have an IPv4 address, and uhppote-rs only talks to them over IPv4.
+const IPV4_ONLY: &str = "UHPPOTE controllers only support IPv4";
+
+/// Config of the bridge, read from a YAML, JSON or TOML file or fetched from a URL.
+#[derive(Deserialize, JsonSchema)]
+pub struct Config {
+    #[serde(default)]
+    pub devices: Vec<DeviceConfig>,
+    /// Legacy single device layout. Converted into `devices` when loading the config.
+    uhppote_device_id: Option<u32>,
+    uhppote_device_ip: Option<String>,
+    #[serde(default)]
+    doors: Vec<DoorConfig>,
+    #[serde(default)]
+    interlock: Vec<Vec<u8>>,
+    /// Legacy single door layout. Converted into `doors` when loading the config.
+    name: Option<String>,
+    door: Option<u8>,
+    base_topic: Option<String>,
+    /// Client id for the MQTT connection. The broker drops the older of two connections with the
+    /// same id, so it has to be unique. Generated from the host name and the device ids when
+    /// omitted. A Docker container gets a new host name when it's recreated unless one is set
+    /// with `--hostname`, so set either that or this in containers.
+    pub mqtt_id: Option<String>,
+    /// Publish Home Assistant discovery configs. Without them the bridge is a plain MQTT bridge,
+    /// configs published by an earlier run are removed when there's a state file.
+    #[serde(default = "default_enable_discovery")]
+    pub enable_discovery: bool,
+    /// Prefix Home Assistant listens on for discovery configs.
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+    /// Topic Home Assistant announces itself on. Discovery is published again when it comes
+    /// online.
+    #[serde(default = "default_birth_topic")]
+    pub birth_topic: String,
+    /// One or more brokers, the next one is tried when a broker can't be reached. A single port
+    /// is used for all hosts.
+    #[serde(default, deserialize_with = "one_or_many")]
+    #[schemars(with = "OneOrMany<String>")]
+    pub mqtt_host: Vec<String>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    #[schemars(with = "OneOrMany<u16>")]
+    pub mqtt_port: Vec<u16>,
+    pub mqtt_username: Option<String>,
+    pub mqtt_password: Option<String>,
+    /// Files to read the username and password from, like Docker secrets. They take precedence
+    /// over `mqtt_username` and `mqtt_password`.
+    pub mqtt_username_file: Option<PathBuf>,
+    pub mqtt_password_file: Option<PathBuf>,
+    pub mqtt_ssl: Option<bool>,
+    #[serde(default)]
+    pub mqtt_transport: MqttTransport,
+    /// Path of the MQTT endpoint when connecting over WebSocket.
+    #[serde(default = "default_mqtt_websocket_path")]
+    pub mqtt_websocket_path: String,
+    /// PEM encoded CA certificate. When omitted, the system root store is used.
+    pub mqtt_ca_cert: Option<String>,
+    /// PEM encoded client certificate and key, for brokers that require client authentication.
+    pub mqtt_client_cert: Option<String>,
+    pub mqtt_client_key: Option<String>,
+    /// Consecutive failed connection attempts after which the bridge exits, so a supervisor can
+    /// restart it. 0 retries forever.
+    #[serde(default)]
+    pub max_reconnect_attempts: u32,
+    /// Seconds to wait before connecting to the MQTT broker, for when it starts at the same time.
+    #[serde(default)]
+    pub startup_delay_secs: u64,
+    /// Keep trying to connect to the MQTT broker on startup until it's ready. Failed attempts
+    /// before the first connection are logged as waiting and don't count towards
+    /// `max_reconnect_attempts`.
+    #[serde(default)]
+    pub wait_for_broker: bool,
+    /// Seconds between MQTT keep-alive pings. Raise this on slow or flaky links.
+    #[serde(default = "default_mqtt_keepalive_secs")]
+    pub mqtt_keepalive_secs: u64,
+    /// Seconds without any MQTT traffic after which the connection is considered hung and is
+    /// set up again. Defaults to 3 keep-alive intervals, 0 disables the watchdog.
+    pub watchdog_timeout_secs: Option<u64>,
+    /// Publish to the MQTT state topic every time the connection to the broker is established,
+    /// for debugging flaky brokers.
+    #[serde(default)]
+    pub publish_mqtt_state: bool,
+    /// Remove the discovery configs when shutting down, so the entities disappear from Home
+    /// Assistant while the bridge isn't running. They're kept by default.
+    #[serde(default)]
+    pub clear_discovery_on_exit: bool,
+    /// QoS level (0, 1 or 2) to subscribe to the command topics with.
+    #[serde(default = "default_command_qos", deserialize_with = "deserialize_qos")]
+    #[schemars(with = "u8")]
+    pub command_qos: QoS,
+    /// QoS level (0, 1 or 2) to publish the discovery configs with.
+    #[serde(
+        default = "default_discovery_qos",
+        deserialize_with = "deserialize_qos"
+    )]
+    #[schemars(with = "u8")]
+    pub discovery_qos: QoS,
+    /// QoS level (0, 1 or 2) to publish the door state with.
+    #[serde(default = "default_state_qos", deserialize_with = "deserialize_qos")]
+    #[schemars(with = "u8")]
+    pub state_qos: QoS,
+    /// Minimum milliseconds between commands for the same door. Commands arriving sooner are
+    /// held back and only the most recent is sent. 0 disables this.
+    #[serde(default)]
+    pub min_command_interval_ms: u64,
+    /// Allow changing the network config of the controllers over MQTT. A wrong config makes a
+    /// controller unreachable, so this is disabled by default.
+    #[serde(default)]
+    pub allow_network_config: bool,
+    /// Allow calling device operations directly over MQTT, for debugging.
+    #[serde(default)]
+    pub enable_raw: bool,
+    /// Seconds the door stays unlocked after it's opened.
+    #[serde(default = "default_open_delay")]
+    pub open_delay: u8,
+    /// Set the delay of the doors to `open_delay` on startup when the devices have another one,
+    /// and before opening a door. Without it, a door opens for the delay the device has.
+    #[serde(default)]
+    pub enforce_delay: bool,
+    /// Seconds between reading the door state from the device. 0 disables polling.
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+    /// Seconds between checking whether the devices are reachable. 0 disables this.
+    #[serde(default = "default_device_poll_interval")]
+    pub device_poll_interval: u64,
+    /// Local address the device sends events to, with port 0 for a port picked by the OS. Events
+    /// aren't published when omitted.
+    pub event_listener: Option<SocketAddr>,
+    /// Doors to publish events of. Events of all doors are published when empty.
+    #[serde(default)]
+    pub event_door_filter: Vec<u8>,
+    /// Also publish the events to `{base_topic}/event`, where all events went before they were
+    /// published to the topics of their doors. Deprecated, turn it off once nothing subscribes to
+    /// it anymore.
+    #[serde(default = "default_legacy_event_topic")]
+    pub legacy_event_topic: bool,
+    /// Publish the index of the most recent event of every device to its event index topic, so
+    /// consumers can tell when they missed events.
+    #[serde(default)]
+    pub publish_event_index: bool,
+    /// File to keep the last state of every door in, so it can be published right after a
+    /// restart. The state isn't published on startup when omitted.
+    pub state_file: Option<PathBuf>,
+    /// File to keep the index of the last published event in, so events that happened while the
+    /// bridge was down are published on startup. Missed events aren't published when omitted.
+    pub event_state_file: Option<PathBuf>,
+    /// File to append a JSON line to for every command and request received and every state and
+    /// event published, for security reviews. Separate from the application log. Every line has
+    /// the hash of the line before it, so removed or changed lines can be detected.
+    pub audit_log: Option<PathBuf>,
+    /// Bytes after which the audit log is moved to `<audit_log>.1` and a new one is started.
+    /// 0 lets it grow forever.
+    #[serde(default)]
+    pub audit_log_max_bytes: u64,
+    /// Syslog server to send access events and commands to as well, as `host:port`. Messages are
+    /// sent over UDP in the RFC 5424 format.
+    pub syslog_target: Option<String>,
+    /// Milliseconds in which an event identical to the previous one of the same device isn't
+    /// published, so a card held against a reader doesn't flood the event topic. 0 disables this.
+    #[serde(default)]
+    pub event_dedup_window_ms: u64,
+    /// Maximum events published per second. Events beyond it are dropped. 0 disables this.
+    #[serde(default)]
+    pub event_rate_limit: u32,
+    /// IANA timezone the device clock is set in. Defaults to the host's timezone.
+    #[schemars(with = "Option<String>")]
+    pub timezone: Option<Tz>,
+    /// Seconds between setting the device clock. 0 only sets it on startup.
+    #[serde(default = "default_time_sync_interval")]
+    pub time_sync_interval: u64,
+    /// Seconds between reading the device clocks to publish their time and drift. 0 disables this.
+    #[serde(default = "default_clock_poll_interval")]
+    pub clock_poll_interval: u64,
+    /// Seconds a device clock may be off before it's reported as a problem.
+    #[serde(default = "default_clock_drift_threshold")]
+    pub clock_drift_threshold: u64,
+    /// Seconds between reading the number of cards stored on the devices. 0 disables this.
+    #[serde(default = "default_card_count_poll_interval")]
+    pub card_count_poll_interval: u64,
+    /// Port to serve Prometheus metrics on. Metrics aren't served when omitted.
+    pub metrics_port: Option<u16>,
+    /// Port to serve a health check on at `/health`. Not served when omitted.
+    pub health_port: Option<u16>,
+    /// Port to serve the REST API for door commands and status on. Not served when omitted.
+    pub http_api_port: Option<u16>,
+    /// Token REST API clients must send as `Authorization: Bearer <token>`. Without it, anyone
+    /// who can reach the port can unlock the doors.
+    pub http_api_token: Option<String>,
+    /// Key MQTT requests must be signed with, for brokers that others can publish to. Requests are
+    /// then sent as `{"cmd": "<payload>", "ts": <unix time in ms>, "sig": "<hex>"}`, where `sig` is
+    /// the HMAC-SHA256 of the topic, `ts` and `cmd`, separated by newlines. Unsigned requests are
+    /// ignored.
+    pub command_hmac_key: Option<String>,
+    /// Seconds a signed request may be older or newer than the bridge's clock.
+    #[serde(default = "default_command_max_age_secs")]
+    pub command_max_age_secs: u64,
+    /// Local address to send requests to the devices from. Use `:0` to let the OS pick a free
+    /// port, so it doesn't conflict with other UHPPOTE software on port 60001.
+    #[serde(
+        default = "default_bind_address",
+        deserialize_with = "deserialize_bind_address"
+    )]
+    pub bind_address: SocketAddr,
+    /// Address to broadcast requests to. Use the broadcast address of the devices' subnet when
+    /// the global broadcast doesn't reach them.
+    #[serde(
+        default = "default_broadcast_address",
+        deserialize_with = "deserialize_broadcast_address"
+    )]
+    pub broadcast_address: Ipv4Addr,
+    /// Seconds to wait for a device to respond. Also accepted as `device_timeout_secs`.
+    #[serde(default = "default_timeout_seconds", alias = "device_timeout_secs")]
+    pub timeout_seconds: u64,
+    /// Times a device request is sent again when the device doesn't answer in time.
+    #[serde(default)]
+    pub device_retries: u32,
+    /// Log every device request with how long it took and what the device answered, at trace
+    /// level, to diagnose devices that answer differently than expected.
+    #[serde(default)]
+    pub trace_packets: bool,
+    /// Requests in a row that time out after which a device is looked up again with a broadcast,
+    /// in case it got another address. 0 never looks it up again.
+    #[serde(default = "default_device_refresh_failures")]
+    pub device_refresh_failures: u32,
+    /// Publish the door state with the retain flag, so Home Assistant knows it after a restart.
+    /// A retained state can be stale after a crash, the poller corrects it on its next run.
+    #[serde(default = "default_retain_state")]
+    pub retain_state: bool,
+    /// Don't publish a state identical to the last one published to the same topic, like when the
+    /// poller reads back the state a command just published.
+    #[serde(default)]
+    pub suppress_duplicate_states: bool,
+    /// Seconds after which the poller publishes an unchanged state anyway, so a restarted Home
+    /// Assistant learns it even without retained states. 0 never publishes it again while it's
+    /// unchanged.
+    #[serde(default)]
+    pub force_publish_interval: u64,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    #[serde(default)]
+    pub state_format: StateFormat,
+    /// TinyTemplate for the payload published to the state topics, in place of `state_format`. It
+    /// can use `{state}`, `{mode}`, `{delay}`, `{changed_at}`, `{door}`, `{name}` and
+    /// `{device_id}`, a literal `{` is written as `\{`. Home Assistant discovery still assumes
+    /// `state_format`.
+    pub state_template: Option<String>,
+    /// TinyTemplate for the payloads on the command topics, with `{command}` where the command is.
+    /// It can use `{door}`, `{name}` and `{device_id}` too. A payload that doesn't match it is an
+    /// unknown command. The discovery configs tell Home Assistant to send payloads like it.
+    pub command_template: Option<String>,
+    /// Strings the door state is published as, for consumers other than Home Assistant. Secured
+    /// doors are always published as `SECURED`.
+    #[serde(
+        default = "default_state_locked",
+        deserialize_with = "string_or_number"
+    )]
+    #[schemars(with = "StringOrNumber")]
+    pub state_locked: String,
+    #[serde(
+        default = "default_state_unlocked",
+        deserialize_with = "string_or_number"
+    )]
+    #[schemars(with = "StringOrNumber")]
+    pub state_unlocked: String,
+    #[serde(default = "default_state_open", deserialize_with = "string_or_number")]
+    #[schemars(with = "StringOrNumber")]
+    pub state_open: String,
+    /// Extra plain payloads to accept on the command topic, e.g. for non-English setups.
+    #[serde(default)]
+    pub command_aliases: Vec<CommandAlias>,
+    /// What to do with payloads on the command topic that aren't a known command. They're logged
+    /// and dropped by default.
+    #[serde(default)]
+    pub unknown_command_action: UnknownCommandAction,
+    /// All plain payloads accepted on the command topic, filled in when loading.
+    #[serde(skip)]
+    pub aliases: HashMap<String, Action>,
+}
+
+/// Extra plain payload accepted on the command topic.
+#[derive(Deserialize, JsonSchema)]
+pub struct CommandAlias {
+    /// Payload to accept, case insensitive
+    pub payload: String,
+    pub action: Action,
+}
+
+/// How to connect to the MQTT broker.
+#[derive(Deserialize, JsonSchema, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MqttTransport {
+    /// Plain MQTT, with TLS when `mqtt_ssl` is set
+    #[default]
+    Tcp,
+    /// MQTT over WebSocket
+    Websocket,
+    /// MQTT over WebSocket with TLS
+    WebsocketTls,
+}
+
+/// What to do with payloads on the command topic that aren't a known command.
+#[derive(Deserialize, JsonSchema, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownCommandAction {
+    /// Drop them silently
+    Ignore,
+    /// Drop them with a warning
+    #[default]
+    Log,
+    /// Publish a failed result, and the payload to the error topic of the door
+    Report,
+}
+
+/// Format of the payloads published to the state topic.
+#[derive(Deserialize, JsonSchema, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StateFormat {
+    /// Just the state, like `LOCKED`
+    #[default]
+    Plain,
+    /// JSON object with the state, mode, delay and when the state last changed
+    Json,
+}
+
+/// Format of the log lines.
+#[derive(Deserialize, JsonSchema, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Home Assistant entity category, which moves an entity out of the main controls of a device.
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum EntityCategory {
+    /// Settings, like the lock of a door that's only used for maintenance
+    Config,
+    /// Status that's only needed when something is wrong
+    Diagnostic,
+}
+
+fn default_enable_discovery() -> bool {
+    true
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+fn default_birth_topic() -> String {
+    "homeassistant/status".to_string()
+}
+
+fn default_mqtt_websocket_path() -> String {
+    "/mqtt".to_string()
+}
+
+fn default_mqtt_keepalive_secs() -> u64 {
+    5
+}
+
+fn default_command_qos() -> QoS {
+    QoS::AtMostOnce
+}
+
+fn default_discovery_qos() -> QoS {
+    QoS::AtLeastOnce
+}
+
+fn default_state_qos() -> QoS {
+    QoS::AtLeastOnce
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+fn one_or_many<'de, D: Deserializer<'de>, T: Deserialize<'de>>(
+    deserializer: D,
+) -> Result<Vec<T>, D::Error> {
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum StringOrNumber {
+    String(String),
+    Number(i64),
+}
+
+/// Accept numbers for strings, so `state_locked: 1` works without quotes.
+fn string_or_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(match StringOrNumber::deserialize(deserializer)? {
+        StringOrNumber::String(value) => value,
+        StringOrNumber::Number(value) => value.to_string(),
+    })
+}
+
+/// Parse a bind address, accepting `:port` for `0.0.0.0:port`.
+fn parse_bind_address(address: &str) -> Result<SocketAddr, std::net::AddrParseError> {
+    match address.strip_prefix(':') {
+        Some(port) => format!("0.0.0.0:{}", port).parse(),
+        None => address.parse(),
+    }
+}
+
+fn deserialize_bind_address<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<SocketAddr, D::Error> {
+    let address = String::deserialize(deserializer)?;
+    parse_bind_address(&address)
+        .map_err(|e| serde::de::Error::custom(format!("invalid bind address '{}': {}", address, e)))
+}
+
+fn deserialize_broadcast_address<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Ipv4Addr, D::Error> {
+    let address = String::deserialize(deserializer)?;
+    if address.parse::<Ipv6Addr>().is_ok() {
+        return Err(serde::de::Error::custom(format!(
+            "invalid broadcast address '{}', IPv6 has no broadcast and {}",
+            address, IPV4_ONLY
+        )));
+    }
+    address.parse().map_err(|e| {
+        serde::de::Error::custom(format!("invalid broadcast address '{}': {}", address, e))
+    })
+}
+
+fn deserialize_optional_bind_address<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<SocketAddr>, D::Error> {
+    deserialize_bind_address(deserializer).map(Some)
+}
+
+fn deserialize_qos<'de, D: Deserializer<'de>>(deserializer: D) -> Result<QoS, D::Error> {
+    match u8::deserialize(deserializer)? {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        2 => Ok(QoS::ExactlyOnce),
+        qos => Err(serde::de::Error::custom(format!(
+            "invalid QoS level {}, expected 0, 1 or 2",
+            qos
+        ))),
+    }
+}
+
+fn default_state_locked() -> String {
+    State::Locked.as_str().to_string()
+}
+
+fn default_state_unlocked() -> String {
+    State::Unlocked.as_str().to_string()
+}
+
+fn default_state_open() -> String {
+    State::Open.as_str().to_string()
+}
+
+fn default_legacy_event_topic() -> bool {
+    true
+}
+
+fn default_open_delay() -> u8 {
+    5
+}
+
+fn default_poll_interval() -> u64 {
+    30
+}
+
+fn default_device_poll_interval() -> u64 {
+    60
+}
+
+fn default_door_sensor() -> bool {
+    true
+}
+
+fn default_time_sync_interval() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_clock_poll_interval() -> u64 {
+    5 * 60
+}
+
+fn default_clock_drift_threshold() -> u64 {
+    60
+}
+
+fn default_card_count_poll_interval() -> u64 {
+    60 * 60
+}
+
+fn default_bind_address() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 60001))
+}
+
+fn default_broadcast_address() -> Ipv4Addr {
+    Ipv4Addr::BROADCAST
+}
+
+fn default_timeout_seconds() -> u64 {
+    5
+}
+
+fn default_device_refresh_failures() -> u32 {
+    5
+}
+
+fn default_command_max_age_secs() -> u64 {
+    30
+}
+
+fn default_retain_state() -> bool {
+    true
+}
+
+impl Config {
+    /// Read the config from `path`, or from stdin when `path` is `-`, converting the legacy
+    /// single device and single door layouts into `devices`. The format is determined by the
+    /// file extension, and guessed for stdin. When `path` is a directory, all config files in
+    /// it are merged into one config.
+    pub fn load(path: &str) -> Result<Config> {
+        let config = if path != "-" && Path::new(path).is_dir() {
+            Config::read_dir(Path::new(path))
+        } else {
+            Config::read(path)
+        };
+        Ok(config
+            .and_then(Config::finish)
+            .map_err(BridgeError::Config)?)
+    }
+
+    /// Fetch the config from `url`. The format is determined by the `Content-Type`, or by the
+    /// extension in the URL. A fetched config is saved to `cache`, and the saved one is used when
+    /// the server can't be reached, which is returned as the error that's worked around.
+    pub async fn fetch(url: &str, cache: Option<&Path>) -> Result<(Config, Option<anyhow::Error>)> {
+        let origin = format!("config from '{}'", url);
+        let (contents, format) = match fetch_contents(url).await {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                let cache = match cache {
+                    Some(cache) => cache,
+                    None => return Err(BridgeError::Network(e).into()),
+                };
+                let contents = match std::fs::read_to_string(cache) {
+                    Ok(contents) => contents,
+                    Err(_) => return Err(BridgeError::Network(e).into()),
+                };
+                let config = Config::parse_any(&contents)
+                    .and_then(Config::finish)
+                    .with_context(|| format!("Failed to parse cached config '{}'", cache.display()))
+                    .map_err(BridgeError::Config)?;
+                return Ok((config, Some(e)));
+            }
+        };
+
+        let config = Config::parse(&contents, format.as_deref(), &origin)
+            .and_then(Config::finish)
+            .map_err(BridgeError::Config)?;
+        if let Some(cache) = cache {
+            std::fs::write(cache, &contents)
+                .with_context(|| format!("Failed to write config cache '{}'", cache.display()))?;
+        }
+        Ok((config, None))
+    }
+
+    /// Convert the legacy single device and single door layouts into `devices`, validate the
+    /// config and fill in what's derived from it.
+    fn finish(mut config: Config) -> Result<Config> {
+        match (
+            config.name.take(),
+            config.door.take(),
+            config.base_topic.take(),
+        ) {
+            (Some(name), Some(door), Some(base_topic)) => config.doors.push(DoorConfig {
+                name,
+                door,
+                base_topic,
+                config_topic: None,
+                door_sensor: default_door_sensor(),
+                auto_relock_secs: None,
+                icon: None,
+                entity_category: None,
+                interlocked: Vec::new(),
+            }),
+            (None, None, None) => {}
+            _ => bail!("'name', 'door' and 'base_topic' must be specified together"),
+        }
+
+        match (
+            config.uhppote_device_id.take(),
+            config.uhppote_device_ip.take(),
+        ) {
+            (Some(uhppote_device_id), uhppote_device_ip) => config.devices.push(DeviceConfig {
+                uhppote_device_id,
+                uhppote_device_ip,
+                doors: std::mem::take(&mut config.doors),
+                interlock: std::mem::take(&mut config.interlock),
+                bind_address: None,
+            }),
+            (None, None) if config.doors.is_empty() && config.interlock.is_empty() => {}
+            (None, None) => bail!("'doors' and 'interlock' must be specified as part of a device"),
+            (None, Some(_)) => {
+                bail!("'uhppote_device_ip' must be specified with 'uhppote_device_id'")
+            }
+        }
+
+        config.aliases = commands::aliases(&config.command_aliases);
+
+        if let Some(path) = &config.mqtt_username_file {
+            config.mqtt_username = Some(read_secret(path)?);
+        }
+        if let Some(path) = &config.mqtt_password_file {
+            config.mqtt_password = Some(read_secret(path)?);
+        }
+
+        for device in &mut config.devices {
+            for door in &mut device.doors {
+                door.interlocked = device
+                    .interlock
+                    .iter()
+                    .filter(|group| group.contains(&door.door))
+                    .flatten()
+                    .filter(|other| **other != door.door)
+                    .copied()
+                    .collect();
+                door.interlocked.sort_unstable();
+                door.interlocked.dedup();
+                door.base_topic = expand_topic(&door.base_topic, device.uhppote_device_id, door)?;
+                if let Some(topic) = &door.config_topic {
+                    door.config_topic = Some(expand_topic(topic, device.uhppote_device_id, door)?);
+                }
+            }
+        }
+
+        // After expanding the topics, so the checks apply to the topics that are used
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Read a single config file, or stdin when `path` is `-`.
+    fn read(path: &str) -> Result<Config> {
+        let contents = if path == "-" {
+            std::io::read_to_string(std::io::stdin()).context("Failed to read config from stdin")?
+        } else {
+            std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read config file '{}'", path))?
+        };
+
+        let format = Path::new(path).extension().and_then(|e| e.to_str());
+        Config::parse(&contents, format, &format!("config file '{}'", path))
+    }
+
+    /// Parse `contents` in `format`, which is a file extension like `json`. Guessed when it's not
+    /// known. `origin` describes where the config came from for errors.
+    fn parse(contents: &str, format: Option<&str>, origin: &str) -> Result<Config> {
+        let config = match format {
+            Some("json") => serde_json::from_str(contents)
+                .with_context(|| format!("Failed to parse JSON {}", origin))?,
+            Some("yaml") | Some("yml") => serde_yaml::from_str(contents)
+                .with_context(|| format!("Failed to parse YAML {}", origin))?,
+            Some("toml") => toml::from_str(contents)
+                .with_context(|| format!("Failed to parse TOML {}", origin))?,
+            _ => Config::parse_any(contents)
+                .with_context(|| format!("Failed to parse {}", origin))?,
+        };
+        Ok(config)
+    }
+
+    /// Merge the JSON, YAML and TOML files in `dir`, in alphabetical order. Their `devices` are
+    /// combined, any other setting may only be set in one of the files.
+    fn read_dir(dir: &Path) -> Result<Config> {
+        let mut paths = std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to read config directory '{}'", dir.display()))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        paths.retain(|path| {
+            path.is_file()
+                && matches!(
+                    path.extension().and_then(|e| e.to_str()),
+                    Some("json" | "yaml" | "yml" | "toml")
+                )
+        });
+        paths.sort();
+        if paths.is_empty() {
+            bail!("No config files found in '{}'", dir.display());
+        }
+
+        let mut merged = serde_json::Map::new();
+        // File each setting came from
+        let mut origins = HashMap::new();
+        for path in &paths {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read config file '{}'", path.display()))?;
+            let value: serde_json::Value = match path.extension().and_then(|e| e.to_str()) {
+                Some("json") => serde_json::from_str(&contents).map_err(anyhow::Error::from),
+                Some("toml") => toml::from_str(&contents).map_err(anyhow::Error::from),
+                _ => serde_yaml::from_str(&contents).map_err(anyhow::Error::from),
+            }
+            .with_context(|| format!("Failed to parse config file '{}'", path.display()))?;
+            let settings = match value {
+                serde_json::Value::Object(settings) => settings,
+                // An empty YAML file
+                serde_json::Value::Null => continue,
+                _ => bail!("Config file '{}' must contain a map", path.display()),
+            };
+
+            for (key, value) in settings {
+                if key == "devices" {
+                    let devices = match value {
+                        serde_json::Value::Array(devices) => devices,
+                        _ => bail!("'devices' in '{}' must be a list", path.display()),
+                    };
+                    if let serde_json::Value::Array(all) = merged
+                        .entry("devices")
+                        .or_insert_with(|| serde_json::Value::Array(Vec::new()))
+                    {
+                        all.extend(devices);
+                    }
+                } else if let Some(origin) = origins.insert(key.clone(), path) {
+                    bail!(
+                        "'{}' is set in both '{}' and '{}'",
+                        key,
+                        origin.display(),
+                        path.display()
+                    );
+                } else {
+                    merged.insert(key, value);
+                }
+            }
+        }
+
+        serde_json::from_value(serde_json::Value::Object(merged))
+            .with_context(|| format!("Failed to parse config directory '{}'", dir.display()))
+    }
+
+    /// Client id for the MQTT connection, `mqtt_id` or one generated from the host name and the
+    /// device ids. The generated id stays the same across restarts, as long as the host name
+    /// does.
+    pub fn mqtt_client_id(&self) -> String {
+        if let Some(id) = &self.mqtt_id {
+            return id.clone();
+        }
+        // Brokers may only accept letters, digits and dashes
+        let hostname: String = hostname()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        let ids: Vec<String> = self
+            .devices
+            .iter()
+            .map(|device| device.uhppote_device_id.to_string())
+            .collect();
+        format!("uhppote-mqtt-{}-{}", hostname, ids.join("-"))
+    }
+
+    /// Time without MQTT traffic after which the event loop is restarted, zero when it never is.
+    pub fn watchdog_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.watchdog_timeout_secs
+                .unwrap_or(3 * self.mqtt_keepalive_secs),
+        )
+    }
+
+    /// MQTT brokers to connect to as host and port, in order of preference.
+    pub fn mqtt_brokers(&self) -> Vec<(String, u16)> {
+        self.mqtt_host
+            .iter()
+            .enumerate()
+            .filter_map(|(i, host)| {
+                let port = match self.mqtt_port.as_slice() {
+                    [port] => *port,
+                    ports => *ports.get(i)?,
+                };
+                Some((host.clone(), port))
+            })
+            .collect()
+    }
+
+    /// Check the values that serde can't, so mistakes show up at startup rather than as
+    /// controller errors when a command comes in.
+    fn validate(&self) -> Result<()> {
+        if self.devices.is_empty() {
+            bail!("No devices configured");
+        }
+
+        if let SocketAddr::V6(address) = self.bind_address {
+            bail!("Invalid 'bind_address' '{}', {}", address, IPV4_ONLY);
+        }
+
+        // The controller only supports delays of 1 to 254 seconds, like for commands
+        if !(1..=254).contains(&self.open_delay) {
+            bail!(
+                "Invalid 'open_delay' {}, it must be between 1 and 254 seconds",
+                self.open_delay
+            );
+        }
+        if let Some(SocketAddr::V6(address)) = self.event_listener {
+            bail!("Invalid 'event_listener' '{}', {}", address, IPV4_ONLY);
+        }
+
+        if self.mqtt_port.len() > 1 && self.mqtt_port.len() != self.mqtt_host.len() {
+            bail!("'mqtt_port' must be a single port or one for every 'mqtt_host'");
+        }
+
+        if !self.mqtt_websocket_path.starts_with('/') {
+            bail!(
+                "'mqtt_websocket_path' must start with '/', got '{}'",
+                self.mqtt_websocket_path
+            );
+        }
+
+        if self.mqtt_keepalive_secs < 5 {
+            bail!("'mqtt_keepalive_secs' must be at least 5");
+        }
+        // An idle connection only has traffic every keep-alive interval
+        if let Some(timeout) = self.watchdog_timeout_secs {
+            if timeout > 0 && timeout <= self.mqtt_keepalive_secs {
+                bail!("'watchdog_timeout_secs' must be longer than 'mqtt_keepalive_secs'");
+            }
+        }
+
+        if self.mqtt_id.as_deref() == Some("") {
+            bail!("'mqtt_id' must not be empty");
+        }
+        if self.http_api_token.as_deref() == Some("") {
+            bail!("'http_api_token' must not be empty");
+        }
+        if self.command_hmac_key.as_deref() == Some("") {
+            bail!("'command_hmac_key' must not be empty");
+        }
+        if let Some(target) = &self.syslog_target {
+            match target.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {}
+                _ => bail!("Invalid 'syslog_target' '{}', it must be host:port", target),
+            }
+        }
+        if self.command_max_age_secs == 0 {
+            bail!("'command_max_age_secs' must be at least 1");
+        }
+        if let Some(template) = &self.state_template {
+            template::check_state(template)?;
+        }
+        if let Some(template) = &self.command_template {
+            template::check_command(template)?;
+        }
+
+        let state_names = [
+            &self.state_locked,
+            &self.state_unlocked,
+            &self.state_open,
+            State::Secured.as_str(),
+        ];
+        for (i, name) in state_names.iter().enumerate() {
+            if name.is_empty() {
+                bail!("'state_locked', 'state_unlocked' and 'state_open' must not be empty");
+            }
+            if state_names[..i].contains(name) {
+                bail!("State '{}' is used for more than one state", name);
+            }
+        }
+
+        if self.timeout_seconds == 0 {
+            bail!("'timeout_seconds' must be at least 1");
+        }
+
+        // Doors of the same device may be spread over several config files
+        let mut doors = HashSet::new();
+        for device in &self.devices {
+            if device.uhppote_device_id == 0 {
+                bail!(
+                    "Invalid 'uhppote_device_id' 0, it must be the serial number of the controller"
+                );
+            }
+
+            if let Some(ip) = &device.uhppote_device_ip {
+                if ip.parse::<Ipv6Addr>().is_ok() {
+                    bail!(
+                        "Invalid 'uhppote_device_ip' '{}' for device {}, {}",
+                        ip,
+                        device.uhppote_device_id,
+                        IPV4_ONLY
+                    );
+                }
+                if ip.parse::<Ipv4Addr>().is_err() {
+                    bail!(
+                        "Invalid 'uhppote_device_ip' '{}' for device {}, it must be an IPv4 address",
+                        ip,
+                        device.uhppote_device_id
+                    );
+                }
+            }
+
+            if let Some(SocketAddr::V6(address)) = device.bind_address {
+                bail!(
+                    "Invalid 'bind_address' '{}' for device {}, {}",
+                    address,
+                    device.uhppote_device_id,
+                    IPV4_ONLY
+                );
+            }
+
+            if device.doors.is_empty() {
+                bail!(
+                    "No doors configured for device {}",
+                    device.uhppote_device_id
+                );
+            }
+
+            for door in &device.doors {
+                if !(1..=4).contains(&door.door) {
+                    bail!(
+                        "Invalid door {} for '{}' on device {}, it must be between 1 and 4",
+                        door.door,
+                        door.name,
+                        device.uhppote_device_id
+                    );
+                }
+                if door.base_topic.is_empty() {
+                    bail!("'base_topic' for '{}' must not be empty", door.name);
+                }
+                if door.base_topic.contains(['+', '#']) {
+                    bail!(
+                        "'base_topic' for '{}' must not contain the MQTT wildcards '+' or '#'",
+                        door.name
+                    );
+                }
+                if door.auto_relock_secs == Some(0) {
+                    bail!("'auto_relock_secs' for '{}' must be at least 1", door.name);
+                }
+                if !doors.insert((device.uhppote_device_id, door.door)) {
+                    bail!(
+                        "Door {} of device {} is configured more than once",
+                        door.door,
+                        device.uhppote_device_id
+                    );
+                }
+            }
+
+            for group in &device.interlock {
+                if group.len() < 2 {
+                    bail!(
+                        "Interlock groups of device {} must contain at least 2 doors",
+                        device.uhppote_device_id
+                    );
+                }
+                if let Some(door) = group.iter().find(|door| !(1..=4).contains(*door)) {
+                    bail!(
+                        "Invalid door {} in 'interlock' of device {}, it must be between 1 and 4",
+                        door,
+                        device.uhppote_device_id
+                    );
+                }
+            }
+        }
+
+        if let Some(door) = self
+            .event_door_filter
+            .iter()
+            .find(|door| !(1..=4).contains(*door))
+        {
+            bail!(
+                "Invalid door {} in 'event_door_filter', it must be between 1 and 4",
+                door
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Parse a config of unknown format, trying JSON first and YAML second.
+    fn parse_any(contents: &str) -> Result<Config> {
+        match serde_json::from_str(contents) {
+            Ok(config) => Ok(config),
+            Err(json_err) => match serde_yaml::from_str(contents) {
+                Ok(config) => Ok(config),
+                Err(yaml_err) => bail!("Not valid JSON ({}) or YAML ({})", json_err, yaml_err),
+            },
+        }
+    }
+
+    /// String `state` is published as on the state topic.
+    pub fn state_name(&self, state: State) -> &str {
+        match state {
+            State::Locked => &self.state_locked,
+            State::Unlocked => &self.state_unlocked,
+            State::Open => &self.state_open,
+            State::Secured => State::Secured.as_str(),
+        }
+    }
+
+    /// MQTT state topic is used for publishing when and how the bridge connected to the broker
+    pub fn mqtt_state_topic(&self) -> String {
+        format!("{}/mqtt_state", self.devices[0].base_topic())
+    }
+
+    /// Availability topic is used to tell Home Assistant whether the bridge is online. There is
+    /// only one MQTT connection, so it's shared by all devices and lives under the first one.
+    pub fn availability_topic(&self) -> String {
+        format!("{}/availability", self.devices[0].base_topic())
+    }
+
+    /// All doors command topic is used for emergency commands to every door of every device
+    pub fn all_command_topic(&self) -> String {
+        format!("{}/all/command", self.devices[0].base_topic())
+    }
+
+    /// All doors result topic is used for the outcome of emergency commands
+    pub fn all_result_topic(&self) -> String {
+        format!("{}/all/result", self.devices[0].base_topic())
+    }
+
+    /// Topic filters to subscribe to for door commands. Doors that share a parent topic, like
+    /// `home/doors/front` and `home/doors/back`, are covered by one `home/doors/+/command`
+    /// subscription. Other doors are subscribed to by their own command topic.
+    pub fn command_filters(&self) -> Vec<String> {
+        let doors: Vec<&DoorConfig> = self.devices.iter().flat_map(|d| &d.doors).collect();
+        let mut siblings: HashMap<&str, usize> = HashMap::new();
+        for door in &doors {
+            if let Some(parent) = door.parent_topic() {
+                *siblings.entry(parent).or_default() += 1;
+            }
+        }
+
+        let mut filters = Vec::new();
+        for door in doors {
+            let filter = match door.parent_topic() {
+                Some(parent) if siblings[parent] > 1 => format!("{}/+/command", parent),
+                _ => door.command_topic(),
+            };
+            if !filters.contains(&filter) {
+                filters.push(filter);
+            }
+        }
+        // An explicit topic may already be covered by a wildcard, like `home/doors/command`
+        // next to `home/+/command`. Subscribing to both would deliver its commands twice.
+        let wildcards: Vec<String> = filters
+            .iter()
+            .filter(|f| f.contains('+'))
+            .cloned()
+            .collect();
+        filters.retain(|f| f.contains('+') || !wildcards.iter().any(|w| matches_filter(w, f)));
+        filters
+    }
+}
+
+/// Name of the host, or `localhost` when it can't be determined. `HOSTNAME` is only a fallback,
+/// since it's easily set to something else than the actual host name.
+pub fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|hostname| hostname.trim().to_string())
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+/// Whether `topic` matches `filter`, which may only contain single level wildcards.
+pub fn matches_filter(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    filter
+        .split('/')
+        .all(|f| matches!(levels.next(), Some(level) if f == "+" || f == level))
+        && levels.next().is_none()
+}
+
+/// A controller and its doors.
+#[derive(Clone, Deserialize, JsonSchema)]
+pub struct DeviceConfig {
+    pub uhppote_device_id: u32,
+    /// Address of the controller. It's looked up with a broadcast on startup when omitted.
+    #[serde(default)]
+    pub uhppote_device_ip: Option<String>,
+    pub doors: Vec<DoorConfig>,
+    /// Groups of doors of which only one may be open at a time, like in a mantrap. A door isn't
+    /// unlocked or opened while another door in its group is open.
+    #[serde(default)]
+    pub interlock: Vec<Vec<u8>>,
+    /// Local address to send requests to this device from, instead of `bind_address`. Give
+    /// every device its own port, or `:0`, when requests to several devices overlap.
+    #[serde(default, deserialize_with = "deserialize_optional_bind_address")]
+    pub bind_address: Option<SocketAddr>,
+}
+
+/// Fetch the config at `url`, and its format as a file extension if the server or the URL tells.
+async fn fetch_contents(url: &str) -> Result<(String, Option<String>)> {
+    let response = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("Failed to fetch config from '{}'", url))?;
+
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_lowercase();
+    let format = if content_type.contains("json") {
+        Some("json".to_string())
+    } else if content_type.contains("yaml") {
+        Some("yaml".to_string())
+    } else if content_type.contains("toml") {
+        Some("toml".to_string())
+    } else {
+        Path::new(response.url().path())
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_string)
+    };
+
+    let contents = response
+        .text()
+        .await
+        .with_context(|| format!("Failed to fetch config from '{}'", url))?;
+    Ok((contents, format))
+}
+
+/// Read a secret from `path`, without the trailing newline most editors add.
+fn read_secret(path: &Path) -> Result<String> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read secret from '{}'", path.display()))?;
+    Ok(contents.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Replace the `{device_id}`, `{door}` and `{name}` placeholders in `topic`.
+fn expand_topic(topic: &str, device_id: u32, door: &DoorConfig) -> Result<String> {
+    // The name would add levels or wildcards to the topic
+    if topic.contains("{name}") && door.name.contains(['+', '#', '/']) {
+        bail!(
+            "Name '{}' can't be used in base topic '{}', it must not contain '+', '#' or '/'",
+            door.name,
+            topic
+        );
+    }
+    let expanded = topic
+        .replace("{device_id}", &device_id.to_string())
+        .replace("{door}", &door.door.to_string())
+        .replace("{name}", &door.name);
+
+    if expanded.contains(['{', '}']) {
+        bail!(
+            "Unknown placeholder in base topic '{}', expected {{device_id}}, {{door}} or {{name}}",
+            topic
+        );
+    }
+
+    Ok(expanded)
+}
+
+impl DeviceConfig {
+    /// Configured address of the controller. It's validated when loading the config.
+    pub fn device_ip(&self) -> Option<Ipv4Addr> {
+        self.uhppote_device_ip
+            .as_ref()
+            .and_then(|ip| ip.parse().ok())
+    }
+
+    /// Base topic for topics that belong to the device rather than a single door. This is the
+    /// base topic of the first door.
+    pub fn base_topic(&self) -> &str {
+        &self.doors[0].base_topic
+    }
+
+    /// Info topic is used to publish the details of the controller
+    pub fn info_topic(&self) -> String {
+        format!("{}/info", self.base_topic())
+    }
+
+    /// Device availability topic is used to publish whether the controller is reachable
+    pub fn device_availability_topic(&self) -> String {
+        format!("{}/device_availability", self.base_topic())
+    }
+
+    /// Last open duration topic is used to publish how many seconds a door was unlocked or open,
+    /// each time it's locked again
+    pub fn last_open_duration_topic(&self, door: u8) -> String {
+        format!("{}/door/{}/last_open_duration", self.base_topic(), door)
+    }
+
+    /// Open duration today topic is used to publish how many seconds a door was unlocked or open
+    /// since midnight
+    pub fn open_duration_today_topic(&self, door: u8) -> String {
+        format!("{}/door/{}/open_duration_today", self.base_topic(), door)
+    }
+
+    /// Button topic is used for enabling and disabling the exit button of a door
+    pub fn button_topic(&self, door: u8) -> String {
+        format!("{}/door/{}/button", self.base_topic(), door)
+    }
+
+    /// Button state topic is used to publish whether the exit button of a door was last enabled
+    /// or disabled
+    pub fn button_state_topic(&self, door: u8) -> String {
+        format!("{}/door/{}/button/state", self.base_topic(), door)
+    }
+
+    /// Event index topic is used to publish the index of the most recent event
+    pub fn event_index_topic(&self) -> String {
+        format!("{}/event_index", self.base_topic())
+    }
+
+    /// Legacy event topic was used to publish all access events of the device, before they were
+    /// published per door
+    pub fn legacy_event_topic(&self) -> String {
+        format!("{}/event", self.base_topic())
+    }
+
+    /// Door event topic is used to publish access events of a single door
+    pub fn door_event_topic(&self, door: u8) -> String {
+        format!("{}/door/{}/event", self.base_topic(), door)
+    }
+
+    /// System event topic is used for access events that aren't about a door
+    pub fn system_event_topic(&self) -> String {
+        format!("{}/system/event", self.base_topic())
+    }
+
+    /// Status get topic is used for requesting the complete status of the device
+    pub fn status_get_topic(&self) -> String {
+        format!("{}/status/get", self.base_topic())
+    }
+
+    /// Status topic is used for publishing the complete status of the device
+    pub fn status_topic(&self) -> String {
+        format!("{}/status", self.base_topic())
+    }
+
+    /// Fault topic is used for publishing the error code of the device
+    pub fn fault_topic(&self) -> String {
+        format!("{}/fault", self.base_topic())
+    }
+
+    /// Controller time topic is used for publishing the time of the device clock
+    pub fn controller_time_topic(&self) -> String {
+        format!("{}/controller_time", self.base_topic())
+    }
+
+    /// Clock drift topic is used for publishing how far the device clock is off
+    pub fn clock_drift_topic(&self) -> String {
+        format!("{}/clock_drift", self.base_topic())
+    }
+
+    /// Card count topic is used for publishing the number of cards stored on the device
+    pub fn card_count_topic(&self) -> String {
+        format!("{}/card_count", self.base_topic())
+    }
+
+    /// Last event topic is used for publishing the most recent access event of the device
+    pub fn last_event_topic(&self) -> String {
+        format!("{}/last_event", self.base_topic())
+    }
+
+    /// Card set topic is used for adding and updating access cards
+    pub fn card_set_topic(&self) -> String {
+        format!("{}/card/set", self.base_topic())
+    }
+
+    /// Card delete topic is used for removing access cards
+    pub fn card_delete_topic(&self) -> String {
+        format!("{}/card/delete", self.base_topic())
+    }
+
+    /// Card privilege topic is used for limiting card access to time profiles
+    pub fn card_privilege_topic(&self) -> String {
+        format!("{}/card/privilege", self.base_topic())
+    }
+
+    /// Card result topic is used for the outcome of card requests
+    pub fn card_result_topic(&self) -> String {
+        format!("{}/card/result", self.base_topic())
+    }
+
+    /// Cards get topic is used for requesting the list of cards
+    pub fn cards_get_topic(&self) -> String {
+        format!("{}/cards/get", self.base_topic())
+    }
+
+    /// Cards list topic is used for publishing the list of cards
+    pub fn cards_list_topic(&self) -> String {
+        format!("{}/cards/list", self.base_topic())
+    }
+
+    /// Network set topic is used for changing the network config of the controller
+    pub fn network_set_topic(&self) -> String {
+        format!("{}/network/set", self.base_topic())
+    }
+
+    /// Network result topic is used for the outcome of network requests
+    pub fn network_result_topic(&self) -> String {
+        format!("{}/network/result", self.base_topic())
+    }
+
+    /// Raw topic is used for calling device operations the bridge doesn't wrap
+    pub fn raw_topic(&self) -> String {
+        format!("{}/raw", self.base_topic())
+    }
+
+    /// Raw result topic is used for the responses to raw requests
+    pub fn raw_result_topic(&self) -> String {
+        format!("{}/raw/result", self.base_topic())
+    }
+}
+
+/// A door of a controller, exposed as a lock in Home Assistant.
+#[derive(Clone, Deserialize, JsonSchema)]
+pub struct DoorConfig {
+    pub name: String,
+    pub door: u8,
+    /// May contain `{device_id}`, `{door}` and `{name}`, which are replaced when loading.
+    pub base_topic: String,
+    /// Topic to publish the discovery config to, for custom setups. Defaults to the standard
+    /// Home Assistant lock discovery topic. Supports the same placeholders as `base_topic`.
+    pub config_topic: Option<String>,
+    /// Whether a door sensor is wired. The device can't tell, so it reports closed otherwise.
+    #[serde(default = "default_door_sensor")]
+    pub door_sensor: bool,
+    /// Seconds after which an unlocked door is locked again. Stays unlocked when omitted.
+    pub auto_relock_secs: Option<u64>,
+    /// Icon of the lock in Home Assistant, like `mdi:gate`. Without it, the icon follows the state.
+    pub icon: Option<String>,
+    /// Entity category of the lock, door sensor and button of this door in Home Assistant.
+    pub entity_category: Option<EntityCategory>,
+    /// Doors that must be closed before this door is unlocked, filled in from `interlock` when
+    /// loading.
+    #[serde(skip)]
+    pub interlocked: Vec<u8>,
+}
+
+impl DoorConfig {
+    /// Topic the discovery config was published to before it moved to the standard Home
+    /// Assistant discovery topic.
+    pub fn legacy_config_topic(&self) -> String {
+        format!("{}/config", &self.base_topic)
+    }
+
+    /// Topic that contains `base_topic`, if it isn't at the root.
+    pub fn parent_topic(&self) -> Option<&str> {
+        self.base_topic
+            .rsplit_once('/')
+            .map(|(parent, _)| parent)
+            .filter(|parent| !parent.is_empty())
+    }
+
+    /// State topic is used for device state updates to Home Assistant
+    pub fn state_topic(&self) -> String {
+        format!("{}/state", &self.base_topic)
+    }
+
+    /// Command topic is used for device commands coming from Home Assistant
+    pub fn command_topic(&self) -> String {
+        format!("{}/command", &self.base_topic)
+    }
+
+    /// Result topic is used for the outcome of every command
+    pub fn result_topic(&self) -> String {
+        format!("{}/result", &self.base_topic)
+    }
+
+    /// Error topic is used to echo payloads that aren't a known command
+    pub fn error_topic(&self) -> String {
+        format!("{}/error", &self.base_topic)
+    }
+
+    /// Door sensor topic is used for the physical open/closed state of the door
+    pub fn door_sensor_topic(&self) -> String {
+        format!("{}/door/state", &self.base_topic)
+    }
+}
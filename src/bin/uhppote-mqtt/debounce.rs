@@ -0,0 +1,100 @@
+use log::{debug, info};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::audit;
+
+/// Limits how often commands are handled for each door, to protect the relay when the command
+/// topic is flooded. Commands that arrive within the interval after the previous one are held
+/// back, and only the most recent of them is handled once the interval has passed.
+pub struct Debouncer {
+    interval: Duration,
+    doors: HashMap<String, Door>,
+}
+
+#[derive(Default)]
+struct Door {
+    last: Option<Instant>,
+    pending: Option<Vec<u8>>,
+    dropped: u32,
+}
+
+impl Door {
+    fn report_dropped(&mut self, topic: &str, interval: Duration) {
+        if self.dropped > 0 {
+            info!(
+                "Dropped {} commands on {} that arrived within {}ms",
+                self.dropped,
+                topic,
+                interval.as_millis()
+            );
+            self.dropped = 0;
+        }
+    }
+}
+
+impl Debouncer {
+    pub fn new(interval: Duration) -> Self {
+        Debouncer {
+            interval,
+            doors: HashMap::new(),
+        }
+    }
+
+    /// Returns the payload when the command on `topic` can be handled right away, holds it back
+    /// otherwise.
+    pub fn submit(&mut self, topic: &str, payload: Vec<u8>) -> Option<Vec<u8>> {
+        if self.interval.is_zero() {
+            return Some(payload);
+        }
+
+        let now = Instant::now();
+        let door = self.doors.entry(topic.to_string()).or_default();
+        match door.last {
+            Some(last) if now < last + self.interval => {
+                debug!("Holding back command on {}", topic);
+                if let Some(dropped) = door.pending.replace(payload) {
+                    audit::dropped(topic, &dropped);
+                    door.dropped += 1;
+                }
+                None
+            }
+            _ => {
+                // A held back command that wasn't handled yet is older than this one, so it
+                // must not run after it
+                if let Some(dropped) = door.pending.take() {
+                    audit::dropped(topic, &dropped);
+                    door.dropped += 1;
+                }
+                door.last = Some(now);
+                door.report_dropped(topic, self.interval);
+                Some(payload)
+            }
+        }
+    }
+
+    /// Wait until the next held back command can be handled, and return its topic and payload.
+    /// Never completes when no commands are held back.
+    pub async fn next(&mut self) -> (String, Vec<u8>) {
+        let due = self
+            .doors
+            .iter()
+            .filter(|(_, door)| door.pending.is_some())
+            .filter_map(|(topic, door)| Some((topic.clone(), door.last? + self.interval)))
+            .min_by_key(|(_, due)| *due);
+
+        let (topic, due) = match due {
+            Some(due) => due,
+            None => return std::future::pending().await,
+        };
+
+        tokio::time::sleep_until(due.into()).await;
+
+        let door = self.doors.get_mut(&topic).unwrap();
+        door.last = Some(Instant::now());
+        door.report_dropped(&topic, self.interval);
+
+        let payload = door.pending.take().unwrap();
+        (topic, payload)
+    }
+}
@@ -0,0 +1,555 @@
+use serde::Serialize;
+
+use crate::config::{Config, DeviceConfig, DoorConfig, EntityCategory, StateFormat};
+use crate::info::ControllerInfo;
+use crate::template;
+
+/// Home Assistant doesn't know about secured doors, so show them as locked.
+fn lock_value_template(config: &Config) -> String {
+    let value = match config.state_format {
+        StateFormat::Plain => "value",
+        StateFormat::Json => "value_json.state",
+    };
+    format!(
+        "{{{{ '{}' if {} == 'SECURED' else {} }}}}",
+        config.state_locked, value, value
+    )
+}
+
+/// Payload Home Assistant sends for `command` to `door`, so it matches `command_template`.
+fn command_payload(
+    config: &Config,
+    device: &DeviceConfig,
+    door: &DoorConfig,
+    command: &str,
+) -> String {
+    match &config.command_template {
+        Some(command_template) => {
+            template::payload_for(command_template, device.uhppote_device_id, door, command)
+        }
+        None => command.to_string(),
+    }
+}
+
+/// Home Assistant MQTT discovery config for a door.
+#[derive(Serialize)]
+pub struct Lock {
+    name: String,
+    unique_id: String,
+    command_topic: String,
+    state_topic: String,
+    value_template: String,
+    payload_lock: String,
+    payload_unlock: String,
+    payload_open: String,
+    state_locked: String,
+    state_unlocked: String,
+    state_open: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    json_attributes_topic: Option<String>,
+    availability_topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entity_category: Option<EntityCategory>,
+    device: DeviceInfo,
+}
+
+/// Home Assistant MQTT discovery config for a button that opens a door once, like a buzzer.
+#[derive(Serialize)]
+pub struct OpenButton {
+    name: String,
+    unique_id: String,
+    command_topic: String,
+    payload_press: String,
+    availability_topic: String,
+    icon: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entity_category: Option<EntityCategory>,
+    device: DeviceInfo,
+}
+
+/// Home Assistant MQTT discovery config for the sensor reporting whether a door is open.
+#[derive(Serialize)]
+pub struct DoorSensor {
+    name: String,
+    unique_id: String,
+    state_topic: String,
+    availability_topic: String,
+    device_class: &'static str,
+    payload_on: &'static str,
+    payload_off: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entity_category: Option<EntityCategory>,
+    device: DeviceInfo,
+}
+
+/// Home Assistant MQTT discovery config for the sensor reporting whether a controller is
+/// reachable.
+#[derive(Serialize)]
+pub struct Connectivity {
+    name: String,
+    unique_id: String,
+    state_topic: String,
+    availability_topic: String,
+    device_class: &'static str,
+    payload_on: &'static str,
+    payload_off: &'static str,
+    entity_category: &'static str,
+    device: DeviceInfo,
+}
+
+/// Home Assistant MQTT discovery config for the sensor reporting faults of a controller.
+#[derive(Serialize)]
+pub struct Fault {
+    name: String,
+    unique_id: String,
+    state_topic: String,
+    value_template: &'static str,
+    json_attributes_topic: String,
+    availability_topic: String,
+    entity_category: &'static str,
+    icon: &'static str,
+    device: DeviceInfo,
+}
+
+/// Home Assistant MQTT discovery config for the sensor reporting the time of a controller's
+/// clock.
+#[derive(Serialize)]
+pub struct ControllerTime {
+    name: String,
+    unique_id: String,
+    state_topic: String,
+    availability_topic: String,
+    device_class: &'static str,
+    entity_category: &'static str,
+    device: DeviceInfo,
+}
+
+/// Home Assistant MQTT discovery config for the sensor reporting how far a controller's clock
+/// is off.
+#[derive(Serialize)]
+pub struct ClockDrift {
+    name: String,
+    unique_id: String,
+    state_topic: String,
+    value_template: &'static str,
+    availability_topic: String,
+    device_class: &'static str,
+    state_class: &'static str,
+    unit_of_measurement: &'static str,
+    entity_category: &'static str,
+    device: DeviceInfo,
+}
+
+/// Home Assistant MQTT discovery config for the sensor reporting whether a controller's clock
+/// is off by more than the threshold.
+#[derive(Serialize)]
+pub struct ClockProblem {
+    name: String,
+    unique_id: String,
+    state_topic: String,
+    value_template: &'static str,
+    availability_topic: String,
+    device_class: &'static str,
+    payload_on: &'static str,
+    payload_off: &'static str,
+    entity_category: &'static str,
+    device: DeviceInfo,
+}
+
+/// Home Assistant MQTT discovery config for the sensor reporting the number of cards stored on
+/// a controller.
+#[derive(Serialize)]
+pub struct CardCount {
+    name: String,
+    unique_id: String,
+    state_topic: String,
+    availability_topic: String,
+    state_class: &'static str,
+    entity_category: &'static str,
+    icon: &'static str,
+    device: DeviceInfo,
+}
+
+/// Home Assistant MQTT discovery config for the sensor summarizing the most recent access event
+/// of a controller.
+#[derive(Serialize)]
+pub struct LastEvent {
+    name: String,
+    unique_id: String,
+    state_topic: String,
+    value_template: &'static str,
+    json_attributes_topic: String,
+    availability_topic: String,
+    entity_category: &'static str,
+    icon: &'static str,
+    device: DeviceInfo,
+}
+
+/// Device block that groups all entities of a controller in Home Assistant.
+#[derive(Serialize)]
+struct DeviceInfo {
+    identifiers: Vec<String>,
+    manufacturer: &'static str,
+    model: &'static str,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sw_version: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    connections: Vec<(&'static str, String)>,
+}
+
+impl DeviceInfo {
+    fn new(device: &DeviceConfig, info: Option<&ControllerInfo>) -> Self {
+        DeviceInfo {
+            identifiers: vec![format!("uhppote_{}", device.uhppote_device_id)],
+            manufacturer: "UHPPOTE",
+            model: "UT0311-L0x",
+            name: format!("UHPPOTE {}", device.uhppote_device_id),
+            sw_version: info.map(|i| i.firmware_version.clone()),
+            connections: info
+                .map(|i| vec![("mac", i.mac_address.clone())])
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl Lock {
+    pub fn new(
+        config: &Config,
+        device: &DeviceConfig,
+        info: Option<&ControllerInfo>,
+        door: &DoorConfig,
+    ) -> Self {
+        let json_attributes_topic = match config.state_format {
+            StateFormat::Plain => None,
+            // The other fields of the state end up as attributes of the lock
+            StateFormat::Json => Some(door.state_topic()),
+        };
+
+        Lock {
+            name: door.name.clone(),
+            unique_id: lock_id(device, door),
+            command_topic: door.command_topic(),
+            state_topic: door.state_topic(),
+            value_template: lock_value_template(config),
+            payload_lock: command_payload(config, device, door, "LOCK"),
+            payload_unlock: command_payload(config, device, door, "UNLOCK"),
+            payload_open: command_payload(config, device, door, "OPEN"),
+            state_locked: config.state_locked.clone(),
+            state_unlocked: config.state_unlocked.clone(),
+            state_open: config.state_open.clone(),
+            json_attributes_topic,
+            availability_topic: config.availability_topic(),
+            icon: door.icon.clone(),
+            entity_category: door.entity_category,
+            device: DeviceInfo::new(device, info),
+        }
+    }
+
+    /// Topic the discovery config for `door` is published to.
+    pub fn topic(config: &Config, device: &DeviceConfig, door: &DoorConfig) -> String {
+        match &door.config_topic {
+            Some(topic) => topic.clone(),
+            None => discovery_topic(config, "lock", device, &format!("door_{}", door.door)),
+        }
+    }
+}
+
+fn lock_id(device: &DeviceConfig, door: &DoorConfig) -> String {
+    format!("uhppote_{}_{}", device.uhppote_device_id, door.door)
+}
+
+impl OpenButton {
+    pub fn new(
+        config: &Config,
+        device: &DeviceConfig,
+        info: Option<&ControllerInfo>,
+        door: &DoorConfig,
+    ) -> Self {
+        OpenButton {
+            name: format!("{} Buzz In", door.name),
+            unique_id: format!("{}_open", lock_id(device, door)),
+            command_topic: door.command_topic(),
+            payload_press: command_payload(config, device, door, "OPEN"),
+            availability_topic: config.availability_topic(),
+            // None of the button device classes fit, so just make it look like a door
+            icon: "mdi:door-open",
+            entity_category: door.entity_category,
+            device: DeviceInfo::new(device, info),
+        }
+    }
+
+    /// Topic the discovery config for `door` is published to.
+    pub fn topic(config: &Config, device: &DeviceConfig, door: &DoorConfig) -> String {
+        discovery_topic(
+            config,
+            "button",
+            device,
+            &format!("door_{}_open", door.door),
+        )
+    }
+}
+
+impl DoorSensor {
+    pub fn new(
+        config: &Config,
+        device: &DeviceConfig,
+        info: Option<&ControllerInfo>,
+        door: &DoorConfig,
+    ) -> Self {
+        DoorSensor {
+            name: format!("{} Door", door.name),
+            unique_id: door_sensor_id(device, door),
+            state_topic: door.door_sensor_topic(),
+            availability_topic: config.availability_topic(),
+            device_class: "door",
+            payload_on: "ON",
+            payload_off: "OFF",
+            // Home Assistant refuses sensors in the config category
+            entity_category: door
+                .entity_category
+                .filter(|category| matches!(category, EntityCategory::Diagnostic)),
+            device: DeviceInfo::new(device, info),
+        }
+    }
+
+    /// Topic the discovery config for `door` is published to.
+    pub fn topic(config: &Config, device: &DeviceConfig, door: &DoorConfig) -> String {
+        discovery_topic(
+            config,
+            "binary_sensor",
+            device,
+            &format!("door_{}_sensor", door.door),
+        )
+    }
+}
+
+impl Connectivity {
+    pub fn new(config: &Config, device: &DeviceConfig, info: Option<&ControllerInfo>) -> Self {
+        Connectivity {
+            name: format!("UHPPOTE {} Connectivity", device.uhppote_device_id),
+            unique_id: connectivity_id(device),
+            state_topic: device.device_availability_topic(),
+            availability_topic: config.availability_topic(),
+            device_class: "connectivity",
+            payload_on: "online",
+            payload_off: "offline",
+            entity_category: "diagnostic",
+            device: DeviceInfo::new(device, info),
+        }
+    }
+
+    /// Topic the discovery config for `device` is published to.
+    pub fn topic(config: &Config, device: &DeviceConfig) -> String {
+        discovery_topic(config, "binary_sensor", device, "connectivity")
+    }
+}
+
+impl Fault {
+    pub fn new(config: &Config, device: &DeviceConfig, info: Option<&ControllerInfo>) -> Self {
+        Fault {
+            name: format!("UHPPOTE {} Fault", device.uhppote_device_id),
+            unique_id: fault_id(device),
+            state_topic: device.fault_topic(),
+            value_template: "{{ value_json.fault }}",
+            // The raw codes end up as attributes
+            json_attributes_topic: device.fault_topic(),
+            availability_topic: config.availability_topic(),
+            entity_category: "diagnostic",
+            icon: "mdi:alert-circle-outline",
+            device: DeviceInfo::new(device, info),
+        }
+    }
+
+    /// Topic the discovery config for `device` is published to.
+    pub fn topic(config: &Config, device: &DeviceConfig) -> String {
+        discovery_topic(config, "sensor", device, "fault")
+    }
+}
+
+impl ControllerTime {
+    pub fn new(config: &Config, device: &DeviceConfig, info: Option<&ControllerInfo>) -> Self {
+        ControllerTime {
+            name: format!("UHPPOTE {} Time", device.uhppote_device_id),
+            unique_id: controller_time_id(device),
+            state_topic: device.controller_time_topic(),
+            availability_topic: config.availability_topic(),
+            device_class: "timestamp",
+            entity_category: "diagnostic",
+            device: DeviceInfo::new(device, info),
+        }
+    }
+
+    /// Topic the discovery config for `device` is published to.
+    pub fn topic(config: &Config, device: &DeviceConfig) -> String {
+        discovery_topic(config, "sensor", device, "controller_time")
+    }
+}
+
+impl ClockDrift {
+    pub fn new(config: &Config, device: &DeviceConfig, info: Option<&ControllerInfo>) -> Self {
+        ClockDrift {
+            name: format!("UHPPOTE {} Clock Drift", device.uhppote_device_id),
+            unique_id: clock_drift_id(device),
+            state_topic: device.clock_drift_topic(),
+            value_template: "{{ value_json.drift_seconds }}",
+            availability_topic: config.availability_topic(),
+            device_class: "duration",
+            state_class: "measurement",
+            unit_of_measurement: "s",
+            entity_category: "diagnostic",
+            device: DeviceInfo::new(device, info),
+        }
+    }
+
+    /// Topic the discovery config for `device` is published to.
+    pub fn topic(config: &Config, device: &DeviceConfig) -> String {
+        discovery_topic(config, "sensor", device, "clock_drift")
+    }
+}
+
+impl ClockProblem {
+    pub fn new(config: &Config, device: &DeviceConfig, info: Option<&ControllerInfo>) -> Self {
+        ClockProblem {
+            name: format!("UHPPOTE {} Clock", device.uhppote_device_id),
+            unique_id: clock_problem_id(device),
+            state_topic: device.clock_drift_topic(),
+            value_template: "{{ value_json.problem }}",
+            availability_topic: config.availability_topic(),
+            device_class: "problem",
+            payload_on: "ON",
+            payload_off: "OFF",
+            entity_category: "diagnostic",
+            device: DeviceInfo::new(device, info),
+        }
+    }
+
+    /// Topic the discovery config for `device` is published to.
+    pub fn topic(config: &Config, device: &DeviceConfig) -> String {
+        discovery_topic(config, "binary_sensor", device, "clock_problem")
+    }
+}
+
+impl CardCount {
+    pub fn new(config: &Config, device: &DeviceConfig, info: Option<&ControllerInfo>) -> Self {
+        CardCount {
+            name: format!("UHPPOTE {} Cards", device.uhppote_device_id),
+            unique_id: card_count_id(device),
+            state_topic: device.card_count_topic(),
+            availability_topic: config.availability_topic(),
+            state_class: "measurement",
+            entity_category: "diagnostic",
+            icon: "mdi:card-account-details-outline",
+            device: DeviceInfo::new(device, info),
+        }
+    }
+
+    /// Topic the discovery config for `device` is published to.
+    pub fn topic(config: &Config, device: &DeviceConfig) -> String {
+        discovery_topic(config, "sensor", device, "card_count")
+    }
+}
+
+impl LastEvent {
+    pub fn new(config: &Config, device: &DeviceConfig, info: Option<&ControllerInfo>) -> Self {
+        LastEvent {
+            name: format!("UHPPOTE {} Last Event", device.uhppote_device_id),
+            unique_id: last_event_id(device),
+            state_topic: device.last_event_topic(),
+            value_template: "{{ 'Granted' if value_json.granted else 'Denied' }} card \
+                             {{ value_json.card_number }} at door {{ value_json.door }}",
+            // The complete event ends up as attributes
+            json_attributes_topic: device.last_event_topic(),
+            availability_topic: config.availability_topic(),
+            entity_category: "diagnostic",
+            icon: "mdi:history",
+            device: DeviceInfo::new(device, info),
+        }
+    }
+
+    /// Topic the discovery config for `device` is published to.
+    pub fn topic(config: &Config, device: &DeviceConfig) -> String {
+        discovery_topic(config, "sensor", device, "last_event")
+    }
+}
+
+/// Topics of all discovery configs published for `config`.
+pub fn topics(config: &Config) -> Vec<String> {
+    let mut topics = Vec::new();
+    if !config.enable_discovery {
+        return topics;
+    }
+    for device in &config.devices {
+        if config.device_poll_interval > 0 {
+            topics.push(Connectivity::topic(config, device));
+        }
+        if config.poll_interval > 0 {
+            topics.push(Fault::topic(config, device));
+        }
+        if config.clock_poll_interval > 0 {
+            topics.push(ControllerTime::topic(config, device));
+            topics.push(ClockDrift::topic(config, device));
+            topics.push(ClockProblem::topic(config, device));
+        }
+        if config.card_count_poll_interval > 0 {
+            topics.push(CardCount::topic(config, device));
+        }
+        if config.event_listener.is_some() {
+            topics.push(LastEvent::topic(config, device));
+        }
+        for door in &device.doors {
+            topics.push(Lock::topic(config, device, door));
+            topics.push(OpenButton::topic(config, device, door));
+            topics.push(DoorSensor::topic(config, device, door));
+        }
+    }
+    topics
+}
+
+/// Discovery topic of an entity, `{discovery_prefix}/{component}/{node_id}/{object_id}/config`.
+/// The node id is the device, so all of its entities are grouped together.
+fn discovery_topic(
+    config: &Config,
+    component: &str,
+    device: &DeviceConfig,
+    object_id: &str,
+) -> String {
+    format!(
+        "{}/{}/uhppote_{}/{}/config",
+        config.discovery_prefix, component, device.uhppote_device_id, object_id
+    )
+}
+
+fn connectivity_id(device: &DeviceConfig) -> String {
+    format!("uhppote_{}_connectivity", device.uhppote_device_id)
+}
+
+fn fault_id(device: &DeviceConfig) -> String {
+    format!("uhppote_{}_fault", device.uhppote_device_id)
+}
+
+fn controller_time_id(device: &DeviceConfig) -> String {
+    format!("uhppote_{}_controller_time", device.uhppote_device_id)
+}
+
+fn clock_drift_id(device: &DeviceConfig) -> String {
+    format!("uhppote_{}_clock_drift", device.uhppote_device_id)
+}
+
+fn clock_problem_id(device: &DeviceConfig) -> String {
+    format!("uhppote_{}_clock_problem", device.uhppote_device_id)
+}
+
+fn card_count_id(device: &DeviceConfig) -> String {
+    format!("uhppote_{}_card_count", device.uhppote_device_id)
+}
+
+fn last_event_id(device: &DeviceConfig) -> String {
+    format!("uhppote_{}_last_event", device.uhppote_device_id)
+}
+
+fn door_sensor_id(device: &DeviceConfig, door: &DoorConfig) -> String {
+    format!("uhppote_{}_{}_door", device.uhppote_device_id, door.door)
+}
@@ -0,0 +1,52 @@
+use thiserror::Error;
+
+use crate::retry::is_timeout;
+
+/// What went wrong, for errors where that decides whether to try again or how to exit. The
+/// wrapped error keeps its message and causes, so wrapping it doesn't change how it's logged.
+#[derive(Debug, Error)]
+pub enum BridgeError {
+    /// Invalid config or MQTT settings. Trying again won't help until the config is fixed.
+    #[error(transparent)]
+    Config(anyhow::Error),
+    /// The MQTT broker couldn't be reached or dropped the connection.
+    #[error(transparent)]
+    Mqtt(anyhow::Error),
+    /// A device didn't respond in time or rejected a request.
+    #[error(transparent)]
+    Device(anyhow::Error),
+    /// A request to another service, like the supervisor or a config URL, failed.
+    #[error(transparent)]
+    Network(anyhow::Error),
+}
+
+impl BridgeError {
+    /// Whether the request that failed may succeed when it's sent again. Devices talk UDP, so
+    /// only requests that timed out are worth sending again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            BridgeError::Config(_) => false,
+            BridgeError::Mqtt(_) | BridgeError::Network(_) => true,
+            BridgeError::Device(e) => is_timeout(e),
+        }
+    }
+
+    /// Exit code for the error, from sysexits(3). A supervisor can tell a config that has to be
+    /// fixed from a service that's down for now.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            // EX_CONFIG
+            BridgeError::Config(_) => 78,
+            // EX_UNAVAILABLE
+            BridgeError::Mqtt(_) | BridgeError::Device(_) => 69,
+            // EX_TEMPFAIL
+            BridgeError::Network(_) => 75,
+        }
+    }
+}
+
+/// Exit code for `e`, 1 when it's not a [`BridgeError`].
+pub fn exit_code(e: &anyhow::Error) -> u8 {
+    e.downcast_ref::<BridgeError>()
+        .map_or(1, BridgeError::exit_code)
+}
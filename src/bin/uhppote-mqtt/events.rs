@@ -0,0 +1,354 @@
+use anyhow::{bail, Result};
+use log::{debug, error, info, warn};
+use rumqttc::{AsyncClient, QoS};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use uhppote_rs::{Device, Event, Status, Uhppoted};
+
+use crate::audit;
+use crate::config::{Config, DeviceConfig};
+use crate::locate::DeviceHandle;
+use crate::retry::retry;
+use crate::syslog;
+use crate::throttle::EventThrottle;
+
+/// [`Uhppoted::listen`] only accepts a plain function, so status messages are handed to the
+/// publishing task through this channel.
+static STATUS: OnceLock<UnboundedSender<Status>> = OnceLock::new();
+
+/// Maximum number of missed events published per device on startup
+const MAX_REPLAY: u32 = 1000;
+
+/// Access event as published to the event topic.
+#[derive(Serialize)]
+pub struct EventPayload {
+    // Sequence number of the event on the controller
+    index: u32,
+    card_number: u32,
+    door: u8,
+    granted: bool,
+    reason: String,
+    timestamp: String,
+}
+
+impl From<&Event> for EventPayload {
+    fn from(event: &Event) -> Self {
+        EventPayload {
+            index: event.index,
+            card_number: event.card_number,
+            door: event.door,
+            granted: event.granted,
+            reason: format!("{:?}", event.reason),
+            timestamp: event.timestamp.format("%Y-%m-%dT%H:%M:%S").to_string(),
+        }
+    }
+}
+
+/// Index of the last published event of every device. When a file is configured, these are
+/// persisted so events that happened while the bridge was down can be published on startup.
+struct EventIndexes {
+    path: Option<PathBuf>,
+    indexes: HashMap<u32, u32>,
+}
+
+impl EventIndexes {
+    fn load(path: Option<PathBuf>) -> Self {
+        let indexes = match &path {
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                    warn!("Ignoring invalid event state file {:?}: {}", path, e);
+                    HashMap::new()
+                }),
+                Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+                Err(e) => {
+                    warn!("Failed to read event state file {:?}: {}", path, e);
+                    HashMap::new()
+                }
+            },
+            None => HashMap::new(),
+        };
+
+        EventIndexes { path, indexes }
+    }
+
+    fn get(&self, device_id: u32) -> Option<u32> {
+        self.indexes.get(&device_id).copied()
+    }
+
+    /// Save `index` as the last published event of the device with `device_id`. The file is
+    /// replaced atomically, so a crash while writing doesn't lose the indexes. It's written on a
+    /// blocking thread, which takes the indexes along and hands them back.
+    async fn set(mut self, device_id: u32, index: u32) -> Self {
+        self.indexes.insert(device_id, index);
+        if self.path.is_none() {
+            return self;
+        }
+        tokio::task::spawn_blocking(move || {
+            let path = self.path.as_ref().unwrap();
+            let tmp = path.with_extension("events.tmp");
+            let contents = serde_json::to_string(&self.indexes).unwrap();
+            if let Err(e) = std::fs::write(&tmp, contents).and_then(|_| std::fs::rename(&tmp, path))
+            {
+                error!("Failed to write event state file {:?}: {}", path, e);
+            }
+            self
+        })
+        .await
+        .unwrap()
+    }
+}
+
+/// Point the listener of all `devices` at `address`, receive the status messages they send and
+/// publish every new event in them to the event topic of its door, unless the [`EventThrottle`]
+/// holds it back. `devices` are in the same order as `config.devices`.
+///
+/// Before that, events that were missed since the last run are read from the devices and
+/// published, if `config.event_state_file` is set. With port 0 in `address`, the OS picks the
+/// port.
+pub async fn listen(
+    client: AsyncClient,
+    uhppoted: &'static Uhppoted,
+    config: Arc<Config>,
+    devices: Vec<Arc<DeviceHandle>>,
+    address: SocketAddr,
+) {
+    let address = match resolve_port(address) {
+        Ok(address) => address,
+        Err(e) => {
+            error!("Failed to bind event listener to {}: {}", address, e);
+            return;
+        }
+    };
+    let mut indexes = EventIndexes::load(config.event_state_file.clone());
+
+    // Device config and the last published event index by device id
+    let mut devices_by_id = HashMap::new();
+    for (device_config, device) in config.devices.iter().zip(&devices) {
+        let id = device_config.uhppote_device_id;
+        let handle = device.clone();
+        match tokio::task::spawn_blocking(move || set_listener(&handle, address))
+            .await
+            .unwrap()
+        {
+            Ok(()) => {
+                let last_index = match (&indexes.path, indexes.get(id)) {
+                    (Some(_), Some(last_index)) => {
+                        match replay(&client, &config, device_config, device, last_index).await {
+                            Ok(last_index) => last_index,
+                            Err(e) => {
+                                error!("Failed to publish missed events of device {}: {}", id, e);
+                                last_index
+                            }
+                        }
+                    }
+                    // Nothing was published before, so there's nothing to catch up on.
+                    (Some(_), None) => last_event_index(device).await.unwrap_or(0),
+                    (None, _) => 0,
+                };
+                // Event indexes start at 1, so 0 means the index isn't known
+                if last_index > 0 {
+                    indexes = indexes.set(id, last_index).await;
+                    publish_index(&client, &config, device_config, last_index).await;
+                }
+                devices_by_id.insert(id, (device_config, last_index));
+            }
+            Err(e) => error!(
+                "Failed to set event listener of device {} to {}: {}",
+                id, address, e
+            ),
+        }
+    }
+
+    if devices_by_id.is_empty() {
+        return;
+    }
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    STATUS.set(tx).expect("Event listener already started");
+
+    let mut throttle = EventThrottle::new(
+        Duration::from_millis(config.event_dedup_window_ms),
+        config.event_rate_limit,
+    );
+
+    info!("Listening for events on {}", address);
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = uhppoted.listen(address, |status| {
+            STATUS.get().unwrap().send(status).ok();
+        }) {
+            error!("Event listener stopped: {}", e);
+        }
+    });
+
+    while let Some(status) = rx.recv().await {
+        let id = status.device_id;
+        let (device_config, last_index) = match devices_by_id.get_mut(&id) {
+            Some(device) => device,
+            None => {
+                warn!("Received status from unknown device {}", status.device_id);
+                continue;
+            }
+        };
+
+        // The last event is repeated in every status message, so only publish it once
+        let event = match status.last_event {
+            Some(event) if event.index != *last_index => event,
+            _ => continue,
+        };
+        *last_index = event.index;
+
+        if throttle.allow(id, &event) {
+            publish(&client, &config, device_config, &event).await;
+        }
+        indexes = indexes.set(id, event.index).await;
+        publish_index(&client, &config, device_config, event.index).await;
+    }
+
+    warn!("Event listener stopped");
+}
+
+/// Publish the events of `device` after `last_index` and return the index of the last one.
+async fn replay(
+    client: &AsyncClient,
+    config: &Config,
+    device_config: &DeviceConfig,
+    device: &Arc<DeviceHandle>,
+    last_index: u32,
+) -> Result<u32> {
+    let latest = last_event_index(device).await?;
+
+    let mut first = if latest < last_index {
+        // The event log wrapped around or was cleared, so everything in it is new.
+        warn!(
+            "Event index went back from {} to {}, publishing all stored events",
+            last_index, latest
+        );
+        1
+    } else {
+        last_index + 1
+    };
+
+    if latest >= first && latest - first >= MAX_REPLAY {
+        warn!(
+            "Missed {} events, only publishing the last {}",
+            latest - first + 1,
+            MAX_REPLAY
+        );
+        first = latest - MAX_REPLAY + 1;
+    }
+
+    if latest >= first {
+        info!("Publishing {} missed events", latest - first + 1);
+    }
+
+    for index in first..=latest {
+        let device = device.clone();
+        match tokio::task::spawn_blocking(move || retry(|| device.get_event(index)))
+            .await
+            .unwrap()
+        {
+            Ok(event) => publish(client, config, device_config, &event).await,
+            Err(e) => error!("Failed to get event {}: {}", index, e),
+        }
+    }
+
+    Ok(latest)
+}
+
+/// Index of the most recent event stored on `device`.
+async fn last_event_index(device: &Arc<DeviceHandle>) -> Result<u32> {
+    let device = device.clone();
+    let status = tokio::task::spawn_blocking(move || retry(|| device.get_status()))
+        .await
+        .unwrap()?;
+    Ok(status.last_event.map_or(0, |event| event.index))
+}
+
+/// Topic to publish `event` of `device` to, or `None` when its door is filtered out. Events
+/// without a door, like those of the controller itself, go to the system event topic.
+fn event_topic(config: &Config, device: &DeviceConfig, event: &Event) -> Option<String> {
+    match event.door {
+        0 => Some(device.system_event_topic()),
+        door if config.event_door_filter.is_empty() || config.event_door_filter.contains(&door) => {
+            Some(device.door_event_topic(door))
+        }
+        _ => None,
+    }
+}
+
+async fn publish(client: &AsyncClient, config: &Config, device: &DeviceConfig, event: &Event) {
+    let topic = match event_topic(config, device, event) {
+        Some(topic) => topic,
+        None => {
+            debug!("Ignoring event {} of door {}", event.index, event.door);
+            return;
+        }
+    };
+
+    let payload = serde_json::to_string(&EventPayload::from(event)).unwrap();
+    info!("Publishing {} to {}", &payload, &topic);
+    audit::event(&topic, &payload);
+    syslog::event(device.uhppote_device_id, event, &payload);
+    // Retained, unlike the event itself, so it can be shown at a glance
+    let last_event_topic = device.last_event_topic();
+    debug!("Publishing {} to {}", &payload, &last_event_topic);
+    client
+        .publish(&last_event_topic, QoS::AtLeastOnce, true, payload.clone())
+        .await
+        .unwrap();
+    if config.legacy_event_topic {
+        let legacy_topic = device.legacy_event_topic();
+        debug!("Publishing {} to {}", &payload, &legacy_topic);
+        client
+            .publish(&legacy_topic, QoS::AtLeastOnce, false, payload.clone())
+            .await
+            .unwrap();
+    }
+    client
+        .publish(&topic, QoS::AtLeastOnce, false, payload)
+        .await
+        .unwrap();
+}
+
+/// Publish `index` as the most recent event of `device`, if enabled. Also includes events that
+/// weren't published, like those of filtered doors.
+pub async fn publish_index(
+    client: &AsyncClient,
+    config: &Config,
+    device: &DeviceConfig,
+    index: u32,
+) {
+    if !config.publish_event_index {
+        return;
+    }
+    let topic = device.event_index_topic();
+    debug!("Publishing {} to {}", index, &topic);
+    client
+        .publish(&topic, QoS::AtLeastOnce, true, index.to_string())
+        .await
+        .unwrap();
+}
+
+/// Replace port 0 in `address` with a free port picked by the OS, so the devices are told the
+/// port the listener actually binds.
+fn resolve_port(address: SocketAddr) -> std::io::Result<SocketAddr> {
+    if address.port() != 0 {
+        return Ok(address);
+    }
+    // The port is released again until the listener binds it, which nothing else is likely to
+    // do in between.
+    std::net::UdpSocket::bind(address)?.local_addr()
+}
+
+fn set_listener(device: &Device, address: SocketAddr) -> Result<()> {
+    match address {
+        SocketAddr::V4(address) => retry(|| device.set_listener(*address.ip(), address.port())),
+        SocketAddr::V6(_) => bail!("The listener address must be an IPv4 address"),
+    }
+}
@@ -0,0 +1,37 @@
+use serde::Serialize;
+use uhppote_rs::Status;
+
+/// Meaning of the known system error codes of a controller.
+const SYSTEM_ERRORS: [(u8, &str); 4] = [
+    (0, "OK"),
+    (1, "Tamper"),
+    (2, "Power loss"),
+    (3, "Clock error"),
+];
+
+/// Fault state of a controller as published to the fault topic.
+#[derive(Serialize)]
+struct FaultPayload {
+    // Meaning of the system error, or the code itself when it's unknown
+    fault: String,
+    system_error: u8,
+    special_info: u8,
+}
+
+/// Meaning of `code`, or the code itself when it's unknown.
+fn describe(code: u8) -> String {
+    SYSTEM_ERRORS
+        .iter()
+        .find(|(known, _)| *known == code)
+        .map_or_else(|| code.to_string(), |(_, meaning)| meaning.to_string())
+}
+
+/// Payload for the fault topic, from `status`.
+pub fn payload(status: &Status) -> String {
+    serde_json::to_string(&FaultPayload {
+        fault: describe(status.system_error),
+        system_error: status.system_error,
+        special_info: status.special_info,
+    })
+    .unwrap()
+}
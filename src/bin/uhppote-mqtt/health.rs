@@ -0,0 +1,63 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::http::Response;
+use crate::metrics::Timestamp;
+
+/// Whether we're currently connected to the MQTT broker.
+pub static MQTT_CONNECTED: AtomicBool = AtomicBool::new(false);
+/// Last time any device answered a query.
+pub static LAST_DEVICE_RESPONSE: Timestamp = Timestamp::new();
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+#[derive(Serialize)]
+struct Health {
+    mqtt_connected: bool,
+    device_reachable: bool,
+    uptime_secs: u64,
+}
+
+/// Start counting uptime.
+pub fn start() {
+    STARTED.get_or_init(Instant::now);
+}
+
+/// How long ago a device may have last answered to still be considered reachable. A few poll
+/// intervals, so a single lost UDP packet doesn't fail the check. Without polling there's no
+/// reason to expect regular responses, so any response at all counts.
+pub fn max_response_age(config: &Config) -> Option<Duration> {
+    [config.poll_interval, config.device_poll_interval]
+        .into_iter()
+        .filter(|interval| *interval > 0)
+        .min()
+        .map(|interval| Duration::from_secs(interval * 3))
+}
+
+/// Response for `GET /health`. The status is `503` when either MQTT or the devices are down.
+pub fn response(max_age: Option<Duration>) -> Response {
+    let mqtt_connected = MQTT_CONNECTED.load(Ordering::Relaxed);
+    let device_reachable = match (LAST_DEVICE_RESPONSE.age(), max_age) {
+        (Some(age), Some(max_age)) => age <= max_age.as_secs(),
+        (Some(_), None) => true,
+        (None, _) => false,
+    };
+    let health = Health {
+        mqtt_connected,
+        device_reachable,
+        uptime_secs: STARTED
+            .get()
+            .map_or(0, |started| started.elapsed().as_secs()),
+    };
+
+    Response::json(
+        if mqtt_connected && device_reachable {
+            "200 OK"
+        } else {
+            "503 Service Unavailable"
+        },
+        serde_json::to_string(&health).unwrap(),
+    )
+}
@@ -0,0 +1,162 @@
+use log::{debug, error, info};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Response to a HTTP request.
+pub struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    /// JSON response with `status`.
+    pub fn json(status: &'static str, body: String) -> Response {
+        Response {
+            status,
+            content_type: "application/json",
+            body,
+        }
+    }
+}
+
+/// HTTP request, with only the parts we need.
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub authorization: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// Maps a request to a response, or `None` if the path is unknown.
+pub type Handler =
+    Arc<dyn Fn(Request) -> Pin<Box<dyn Future<Output = Option<Response>> + Send>> + Send + Sync>;
+
+/// Largest request we accept, including the body.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Time a client gets to send its request, so clients that connect and send nothing don't tie
+/// up the server.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Read a single HTTP request from `stream`. Returns `None` if the connection is closed or the
+/// request is too large.
+async fn read_request(stream: &mut TcpStream) -> std::io::Result<Option<Request>> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    let header_end = loop {
+        if let Some(i) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buf[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&request[..header_end]).into_owned();
+    let mut lines = head.lines();
+    let mut parts = lines.next().unwrap_or_default().split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Ok(None),
+    };
+
+    let mut authorization = None;
+    let mut content_length = 0;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.to_string());
+            } else if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.parse().unwrap_or(0);
+            }
+        }
+    }
+    if header_end + content_length > MAX_REQUEST_SIZE {
+        return Ok(None);
+    }
+
+    let mut body = request.split_off(header_end);
+    while body.len() < content_length {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        body.extend_from_slice(&buf[..n]);
+    }
+    body.truncate(content_length);
+
+    Ok(Some(Request {
+        method,
+        path,
+        authorization,
+        body,
+    }))
+}
+
+/// Answer a single HTTP request on `stream`. Requests and responses are small, so there's no
+/// need for a full HTTP server.
+async fn handle(mut stream: TcpStream, handler: Handler) -> std::io::Result<()> {
+    let request = match tokio::time::timeout(READ_TIMEOUT, read_request(&mut stream)).await {
+        Ok(request) => request?,
+        Err(_) => {
+            debug!("Timed out reading HTTP request");
+            return Ok(());
+        }
+    };
+    let response = match request {
+        Some(request) => handler(request).await,
+        None => return Ok(()),
+    };
+
+    let response = match response {
+        Some(response) => format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            response.status,
+            response.content_type,
+            response.body.len(),
+            response.body
+        ),
+        None => {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        }
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Serve HTTP requests on `port` using `handler`.
+pub async fn serve(port: u16, handler: Handler) {
+    let address = SocketAddr::from(([0, 0, 0, 0], port));
+    let listener = match TcpListener::bind(address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to listen on {}: {}", address, e);
+            return;
+        }
+    };
+
+    info!("Serving HTTP on {}", address);
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle(stream, handler).await {
+                        debug!("Failed to answer HTTP request: {}", e);
+                    }
+                });
+            }
+            Err(e) => error!("Failed to accept HTTP connection: {}", e),
+        }
+    }
+}
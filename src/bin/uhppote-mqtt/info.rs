@@ -0,0 +1,86 @@
+use anyhow::Result;
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use uhppote_rs::{Device, DoorControl};
+
+use crate::commands::copy_mode;
+use crate::config::DoorConfig;
+use crate::retry::retry;
+
+/// Controller details as published to the info topic.
+#[derive(Serialize)]
+pub struct ControllerInfo {
+    pub serial_number: u32,
+    pub firmware_version: String,
+    pub release_date: String,
+    pub mac_address: String,
+    pub ip_address: String,
+    // Seconds each configured door stays unlocked after opening, by door number
+    pub door_delays: BTreeMap<u8, u64>,
+}
+
+impl ControllerInfo {
+    /// Read the details of `device` and the delays of `doors`. The delays are compared to
+    /// `open_delay`, and set to it when `enforce_delay` is set.
+    pub fn query(
+        device: &Device,
+        doors: &[DoorConfig],
+        open_delay: Duration,
+        enforce_delay: bool,
+    ) -> Result<ControllerInfo> {
+        let config = retry(|| device.get_config())?;
+        Ok(ControllerInfo {
+            serial_number: config.id,
+            firmware_version: config.version,
+            release_date: config.date.format("%Y-%m-%d").to_string(),
+            mac_address: config.mac,
+            ip_address: config.address.to_string(),
+            door_delays: door_delays(device, doors, open_delay, enforce_delay)?,
+        })
+    }
+}
+
+/// Delays of `doors`. The controller keeps its own delay, which may have been set to something
+/// surprising with other software, so warn about delays that differ from `open_delay`.
+fn door_delays(
+    device: &Device,
+    doors: &[DoorConfig],
+    open_delay: Duration,
+    enforce_delay: bool,
+) -> Result<BTreeMap<u8, u64>> {
+    let mut delays = BTreeMap::new();
+    for door in doors {
+        let control = retry(|| device.get_door_control(door.door))?;
+        let mut delay = control.delay;
+        if delay != open_delay {
+            warn!(
+                "Door {} ('{}') has a delay of {}s instead of {}s",
+                door.door,
+                door.name,
+                delay.as_secs(),
+                open_delay.as_secs()
+            );
+            if enforce_delay {
+                info!(
+                    "Setting delay of door {} to {}s",
+                    door.door,
+                    open_delay.as_secs()
+                );
+                retry(|| {
+                    device.set_door_control_state(
+                        door.door,
+                        DoorControl {
+                            delay: open_delay,
+                            mode: copy_mode(&control.mode),
+                        },
+                    )
+                })?;
+                delay = open_delay;
+            }
+        }
+        delays.insert(door.door, delay.as_secs());
+    }
+    Ok(delays)
+}
@@ -0,0 +1,230 @@
+use anyhow::Result;
+use log::{error, info, warn};
+use std::net::Ipv4Addr;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uhppote_rs::{Device, Uhppoted};
+
+use crate::health;
+use crate::retry::{is_timeout, retry};
+
+/// Maximum number of seconds to wait between checks of an unreachable device
+const MAX_BACKOFF: u64 = 300;
+
+/// Where a device was found on startup.
+pub struct Location {
+    // Address to send requests to, `None` to broadcast them
+    pub ip: Option<Ipv4Addr>,
+    pub reachable: bool,
+}
+
+/// Find the device with `id`. With a configured `ip`, check that the device answers there, and
+/// look for it with a broadcast when it doesn't so the error can tell where it is instead.
+/// Without an `ip`, the device is looked up with a broadcast.
+pub fn locate(uhppoted: &'static Uhppoted, id: u32, ip: Option<Ipv4Addr>) -> Location {
+    let ip = match ip {
+        Some(ip) => ip,
+        None => {
+            return match find(uhppoted, id) {
+                Some(found) => {
+                    info!("Found device {} at {}", id, found);
+                    Location {
+                        ip: Some(found),
+                        reachable: true,
+                    }
+                }
+                None => {
+                    error!(
+                        "Device {} wasn't found on the local network. Check that it's powered \
+                         and on the same subnet, or set 'uhppote_device_ip'",
+                        id
+                    );
+                    Location {
+                        ip: None,
+                        reachable: false,
+                    }
+                }
+            };
+        }
+    };
+
+    let e = match retry(|| uhppoted.get_device(id, Some(ip)).get_status()) {
+        Ok(_) => {
+            health::LAST_DEVICE_RESPONSE.set_now();
+            return Location {
+                ip: Some(ip),
+                reachable: true,
+            };
+        }
+        Err(e) => e,
+    };
+
+    match find(uhppoted, id) {
+        Some(found) if found != ip => error!(
+            "Device {} doesn't respond at {} ({}), but was found at {}. Set 'uhppote_device_ip' \
+             to {}",
+            id, ip, e, found, found
+        ),
+        Some(_) => error!(
+            "Device {} answers broadcasts but doesn't respond at {} ({}). Check the firewall \
+             between the bridge and the controller",
+            id, ip, e
+        ),
+        None => error!(
+            "Device {} doesn't respond at {} ({}) and wasn't found on the local network. Check \
+             'uhppote_device_id' and 'uhppote_device_ip', and that the controller is powered",
+            id, ip, e
+        ),
+    }
+
+    Location {
+        ip: Some(ip),
+        reachable: false,
+    }
+}
+
+/// Handle of a device that's looked up again with a broadcast when it stops responding, like
+/// after a power cycle gave it another address from DHCP. Derefs to the current [`Device`].
+pub struct DeviceHandle {
+    uhppoted: &'static Uhppoted,
+    id: u32,
+    // Whether the address is set in the config, rather than found by broadcast
+    configured: bool,
+    // Requests in a row that timed out after which the device is looked up again, 0 never
+    refresh_after: u32,
+    failures: AtomicU32,
+    // Current address, and a handle for it. A handle is only created when the address changes,
+    // so leaking it to hand out references is fine.
+    device: Mutex<(Option<Ipv4Addr>, &'static Device<'static>)>,
+}
+
+impl DeviceHandle {
+    pub fn new(
+        uhppoted: &'static Uhppoted,
+        id: u32,
+        location: &Location,
+        configured: bool,
+        refresh_after: u32,
+    ) -> Self {
+        let device = Box::leak(Box::new(uhppoted.get_device(id, location.ip)));
+        DeviceHandle {
+            uhppoted,
+            id,
+            configured,
+            refresh_after,
+            failures: AtomicU32::new(0),
+            device: Mutex::new((location.ip, device)),
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Keep track of the `result` of a request to the device, and return it. After
+    /// `refresh_after` requests in a row that timed out, the device is looked up again. Blocks
+    /// while it's looked up.
+    pub fn track<T>(&self, result: Result<T>) -> Result<T> {
+        match &result {
+            Ok(_) => self.failures.store(0, Ordering::Relaxed),
+            Err(e) if is_timeout(e) => {
+                let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if self.refresh_after > 0 && failures.is_multiple_of(self.refresh_after) {
+                    self.refresh(failures);
+                }
+            }
+            // Like a rejected command, the device did answer
+            Err(_) => {}
+        }
+        result
+    }
+
+    fn refresh(&self, failures: u32) {
+        warn!(
+            "Device {} didn't respond {} times in a row, looking for it on the local network",
+            self.id, failures
+        );
+        let found = match find(self.uhppoted, self.id) {
+            Some(found) => found,
+            None => {
+                warn!("Device {} wasn't found on the local network", self.id);
+                return;
+            }
+        };
+
+        let mut device = self.device.lock().unwrap();
+        if device.0 == Some(found) {
+            info!(
+                "Device {} is still at {}, it may be busy or restarting",
+                self.id, found
+            );
+            return;
+        }
+        match device.0 {
+            Some(old) => info!("Device {} moved from {} to {}", self.id, old, found),
+            None => info!("Found device {} at {}", self.id, found),
+        }
+        if self.configured {
+            warn!(
+                "Using {} for device {} until the bridge restarts. Set 'uhppote_device_ip' to {}",
+                found, self.id, found
+            );
+        }
+        *device = (
+            Some(found),
+            Box::leak(Box::new(self.uhppoted.get_device(self.id, Some(found)))),
+        );
+    }
+}
+
+impl Deref for DeviceHandle {
+    type Target = Device<'static>;
+
+    fn deref(&self) -> &Device<'static> {
+        self.device.lock().unwrap().1
+    }
+}
+
+/// Address of the device with `id` according to a broadcast, if it answered.
+fn find(uhppoted: &Uhppoted, id: u32) -> Option<Ipv4Addr> {
+    match uhppoted.get_device_configs() {
+        Ok(configs) => configs
+            .into_iter()
+            .find(|config| config.id == id)
+            .map(|config| config.address),
+        Err(e) => {
+            warn!("Failed to look for devices on the local network: {}", e);
+            None
+        }
+    }
+}
+
+/// Check `device` with an increasing interval until it responds, for devices that weren't
+/// reachable on startup.
+pub async fn wait_until_reachable(device: Arc<DeviceHandle>, id: u32) {
+    let mut backoff = 5;
+    loop {
+        tokio::time::sleep(Duration::from_secs(backoff)).await;
+        let device = device.clone();
+        let result =
+            tokio::task::spawn_blocking(move || device.track(retry(|| device.get_status())))
+                .await
+                .unwrap();
+        match result {
+            Ok(_) => {
+                health::LAST_DEVICE_RESPONSE.set_now();
+                info!("Device {} responds now", id);
+                return;
+            }
+            Err(e) => {
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                warn!(
+                    "Device {} still doesn't respond ({}), checking again in {}s",
+                    id, e, backoff
+                );
+            }
+        }
+    }
+}
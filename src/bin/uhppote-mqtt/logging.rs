@@ -0,0 +1,30 @@
+use chrono::{SecondsFormat, Utc};
+use log::LevelFilter;
+use serde_json::json;
+use std::io::Write;
+
+use crate::config::LogFormat;
+
+/// Initialize the logger. The level is still taken from `RUST_LOG`, apart from the traces of
+/// device requests, which are always logged with `trace_packets`.
+///
+/// Log call sites don't know which device they're about, so the `device_id` of JSON logs is
+/// only set when a single device is configured.
+pub fn init(format: LogFormat, device_id: Option<u32>, trace_packets: bool) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if trace_packets {
+        builder.filter_module("uhppote_mqtt::retry", LevelFilter::Trace);
+    }
+    if let LogFormat::Json = format {
+        builder.format(move |buf, record| {
+            let line = json!({
+                "level": record.level().as_str(),
+                "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                "message": record.args().to_string(),
+                "device_id": device_id,
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
@@ -0,0 +1,1850 @@
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
+use log::{debug, error, info, warn};
+use rumqttc::{
+    AsyncClient, ClientConfig, ConnectReturnCode, ConnectionError, Event, Event::Incoming,
+    EventLoop, LastWill, MqttOptions, Outgoing, Packet, Publish, QoS, Transport,
+};
+use rustls::{Certificate, PrivateKey, RootCertStore, SignatureScheme};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use trust_dns_resolver::AsyncResolver;
+use uhppote_rs::Uhppoted;
+
+use crate::commands::{
+    handle_payload, AllCommandResult, CommandResult, DoorResult, Emergency, Transition,
+    UnknownCommand,
+};
+use crate::config::{
+    matches_filter, Config, DeviceConfig, DoorConfig, MqttTransport, UnknownCommandAction,
+};
+use crate::debounce::Debouncer;
+use crate::error::BridgeError;
+use crate::info::ControllerInfo;
+use crate::locate::DeviceHandle;
+use crate::relock::Relocker;
+use crate::signature::Verifier;
+use crate::state::{DiscoveryVersions, StateFile};
+
+mod api;
+mod audit;
+mod button;
+mod cards;
+mod clock;
+mod commands;
+mod config;
+mod debounce;
+mod discovery;
+mod error;
+mod events;
+mod fault;
+mod health;
+mod http;
+mod info;
+mod locate;
+mod logging;
+mod metrics;
+mod network;
+mod open_time;
+mod poller;
+mod raw;
+mod relock;
+mod retry;
+mod selftest;
+mod signature;
+mod state;
+mod status;
+mod syslog;
+mod template;
+mod throttle;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Maximum number of seconds to wait between MQTT reconnection attempts
+const MAX_BACKOFF: u64 = 60;
+
+/// Failed connection attempts after which we switch to the next MQTT broker, if there is one
+const FAILOVER_ATTEMPTS: u32 = 3;
+
+/// Maximum time to wait for the MQTT broker when disconnecting on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connections that are lost this soon after connecting, several times in a row, are likely
+/// taken over by another client with the same client id
+const SHORT_SESSION: Duration = Duration::from_secs(10);
+const SHORT_SESSIONS: u32 = 3;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// Config file location, a directory of config files to merge, an http(s) URL to fetch the
+    /// config from, or `-` to read the config from stdin
+    #[clap(short, long, value_parser=config_path, required_unless_present = "print-schema")]
+    config: Option<String>,
+
+    /// File to save a config fetched from a URL in, which is used when the server can't be
+    /// reached on a later start
+    #[clap(long)]
+    config_cache: Option<PathBuf>,
+
+    /// Don't send commands to the devices, only log them and publish the expected state
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Log every device request with its duration and result, like `trace_packets` in the config
+    #[clap(long)]
+    trace_packets: bool,
+
+    /// Only check the config and exit, without connecting to MQTT or the devices
+    #[clap(long)]
+    check: bool,
+
+    /// Check that the devices respond by reading their status, time and door states, print a
+    /// report and exit, without connecting to MQTT
+    #[clap(long)]
+    self_test: bool,
+
+    /// Also lock, unlock and lock every door again during the self-test
+    #[clap(long, requires = "self-test")]
+    self_test_cycle: bool,
+
+    /// Print the JSON Schema of the config and exit, for validation and completion in editors
+    #[clap(long)]
+    print_schema: bool,
+}
+
+/// Resolve the config file to an absolute path, so it's clear which file was loaded.
+fn config_path(filename: &str) -> Result<String, String> {
+    if filename == "-" || is_url(filename) {
+        return Ok(filename.to_string());
+    }
+    match Path::new(filename).canonicalize() {
+        Ok(path) => Ok(path.to_string_lossy().into_owned()),
+        Err(_) => Err(format!("File '{}' does not exist", filename)),
+    }
+}
+
+fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+/// Connection to the broker as published to the MQTT state topic.
+#[derive(Serialize)]
+struct MqttState {
+    state: &'static str,
+    broker: String,
+    // Failed attempts before this connection was established
+    failed_attempts: u32,
+}
+
+#[derive(Deserialize)]
+struct HassResult {
+    #[allow(dead_code)]
+    result: String,
+    data: MqttConfig,
+}
+
+#[derive(Deserialize)]
+struct MqttConfig {
+    #[allow(dead_code)]
+    addon: String,
+    host: String,
+    port: u16,
+    ssl: bool,
+    username: String,
+    password: String,
+    #[allow(dead_code)]
+    protocol: String,
+}
+
+fn main() -> ExitCode {
+    match start() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(error::exit_code(&e))
+        }
+    }
+}
+
+fn start() -> Result<()> {
+    // The bridge mostly waits on MQTT and the devices, and blocking device requests run on the
+    // blocking pool, so a single worker is enough for a few doors. Setups with many devices and
+    // background tasks can use more.
+    let worker_threads = match std::env::var("RUNTIME_WORKER_THREADS") {
+        Ok(threads) => threads
+            .parse()
+            .with_context(|| format!("Invalid RUNTIME_WORKER_THREADS '{}'", threads))
+            .map_err(BridgeError::Config)?,
+        Err(_) => 1,
+    };
+    if worker_threads == 0 {
+        return Err(
+            BridgeError::Config(anyhow!("RUNTIME_WORKER_THREADS must be at least 1")).into(),
+        );
+    }
+
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(worker_threads)
+        .enable_all()
+        .build()?
+        .block_on(run())
+}
+
+async fn run() -> Result<()> {
+    let args = Args::parse();
+
+    if args.print_schema {
+        let schema = schemars::schema_for!(Config);
+        println!("{}", serde_json::to_string_pretty(&schema)?);
+        return Ok(());
+    }
+    // Only optional with --print-schema
+    let config_path = args.config.as_deref().unwrap();
+
+    // Read config file
+    let (mut config, fetch_error) = if is_url(config_path) {
+        Config::fetch(config_path, args.config_cache.as_deref()).await?
+    } else {
+        (Config::load(config_path)?, None)
+    };
+    apply_env(&mut config).map_err(BridgeError::Config)?;
+    config.trace_packets |= args.trace_packets;
+
+    if args.check {
+        return Ok(check(config_path, &config).map_err(BridgeError::Config)?);
+    }
+    if args.self_test {
+        retry::set_retries(config.device_retries);
+        let passed = tokio::task::block_in_place(|| selftest::run(&config, args.self_test_cycle));
+        if !passed {
+            return Err(BridgeError::Device(anyhow!("The self-test failed")).into());
+        }
+        return Ok(());
+    }
+
+    let device_id = match config.devices.as_slice() {
+        [device] => Some(device.uhppote_device_id),
+        _ => None,
+    };
+    logging::init(config.log_format, device_id, config.trace_packets);
+    health::start();
+    if let Some(path) = &config.audit_log {
+        audit::start(path.clone(), config.audit_log_max_bytes);
+    }
+    if let Some(target) = &config.syslog_target {
+        syslog::start(target.clone());
+    }
+
+    info!("uhppote-mqtt v{}", VERSION);
+    match (config_path, fetch_error) {
+        ("-", _) => info!("Loaded config from stdin"),
+        (_, Some(e)) => warn!(
+            "{:#}, loaded the config cached in {:?} instead",
+            e,
+            args.config_cache.as_ref().unwrap()
+        ),
+        (path, None) => info!("Loaded config from {}", path),
+    }
+    if args.dry_run {
+        warn!("Running in dry run mode, commands are NOT sent to the devices");
+    }
+
+    retry::set_retries(config.device_retries);
+    retry::set_trace(config.trace_packets);
+
+    // Uhppoted lives for the duration of the process, so the devices can be shared with
+    // background tasks. Requests are sent from its bind address, so there's one per address.
+    let new_uhppoted = |bind_address| -> &'static Uhppoted {
+        Box::leak(Box::new(Uhppoted::new(
+            bind_address,
+            config.broadcast_address,
+            Duration::from_secs(config.timeout_seconds),
+        )))
+    };
+    let uhppoted = new_uhppoted(config.bind_address);
+    let mut uhppoted_by_address = HashMap::from([(config.bind_address, uhppoted)]);
+
+    // Device handles and their details, in the same order as `config.devices`
+    let mut devices = Vec::new();
+    let mut infos = Vec::new();
+    for device_config in &config.devices {
+        let id = device_config.uhppote_device_id;
+        let bind_address = device_config.bind_address.unwrap_or(config.bind_address);
+        let uhppoted = *uhppoted_by_address
+            .entry(bind_address)
+            .or_insert_with(|| new_uhppoted(bind_address));
+        let ip = device_config.device_ip();
+        let location = tokio::task::spawn_blocking(move || locate::locate(uhppoted, id, ip))
+            .await
+            .unwrap();
+        let device = Arc::new(DeviceHandle::new(
+            uhppoted,
+            id,
+            &location,
+            device_config.uhppote_device_ip.is_some(),
+            config.device_refresh_failures,
+        ));
+
+        // Every request would time out, so don't hold up the startup with them
+        if !location.reachable {
+            tokio::spawn(locate::wait_until_reachable(device.clone(), id));
+            devices.push(device);
+            infos.push(None);
+            continue;
+        }
+
+        let timezone = config.timezone;
+        if let Err(e) = on_device(&device, move |device| clock::sync_time(device, timezone)).await {
+            metrics::DEVICE_ERRORS.inc();
+            error!(
+                "Failed to sync time of device {}: {}",
+                device_config.uhppote_device_id, e
+            );
+        }
+
+        // Don't touch the devices in dry run mode
+        let enforce_delay = config.enforce_delay && !args.dry_run;
+        let doors = device_config.doors.clone();
+        let open_delay = Duration::from_secs(config.open_delay.into());
+        let info = match on_device(&device, move |device| {
+            ControllerInfo::query(device, &doors, open_delay, enforce_delay)
+        })
+        .await
+        {
+            Ok(info) => {
+                health::LAST_DEVICE_RESPONSE.set_now();
+                Some(info)
+            }
+            Err(e) => {
+                metrics::DEVICE_ERRORS.inc();
+                warn!(
+                    "Failed to get details of device {}: {}",
+                    device_config.uhppote_device_id, e
+                );
+                None
+            }
+        };
+
+        devices.push(device);
+        infos.push(info);
+    }
+    let infos = Arc::new(infos);
+    // Configs that are removed on exit always have to be published again
+    let versions = Arc::new(Mutex::new(DiscoveryVersions::load(
+        config
+            .state_file
+            .as_deref()
+            .filter(|_| !config.clear_discovery_on_exit),
+    )));
+
+    // Get config from HASS. This takes precedence over the config file and environment.
+    if std::env::var("SUPERVISOR_TOKEN").is_ok() {
+        info!("Getting MQTT config from HASS");
+        let j = hass_mqtt_config().await.map_err(BridgeError::Network)?;
+        config.mqtt_host = vec![j.data.host];
+        config.mqtt_port = vec![j.data.port];
+        config.mqtt_ssl = Some(j.data.ssl);
+        config.mqtt_username = Some(j.data.username);
+        config.mqtt_password = Some(j.data.password);
+    }
+
+    let mqtt_ssl = mqtt_ssl(&config).map_err(BridgeError::Config)?;
+    let brokers = config.mqtt_brokers();
+    if brokers.is_empty() {
+        return Err(BridgeError::Config(anyhow!("No MQTT host found")).into());
+    }
+    let client_id = config.mqtt_client_id();
+    info!("Using MQTT client id '{}'", client_id);
+    let mut broker_options = Vec::new();
+    for (host, port) in &brokers {
+        broker_options.push(mqtt_options(&config, host, *port, mqtt_ssl).await?);
+    }
+    // Index of the broker we're connecting to
+    let mut broker = 0;
+
+    let (client, mut eventloop) = AsyncClient::new(broker_options[broker].clone(), 10);
+    let config = Arc::new(config);
+
+    // Commands from the REST API are handled by the main loop
+    let (api_sender, mut api_commands) = mpsc::channel(10);
+    let api = Arc::new(api::Api::new(config.clone(), devices.clone(), api_sender));
+    if config.http_api_port.is_some() && config.http_api_token.is_none() {
+        warn!("The REST API doesn't require a token, set 'http_api_token' to protect it");
+    }
+
+    // The metrics, health check and API may share a port, so serve each port only once.
+    let max_response_age = health::max_response_age(&config);
+    let mut http_ports = [
+        config.metrics_port,
+        config.health_port,
+        config.http_api_port,
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+    http_ports.sort_unstable();
+    http_ports.dedup();
+    for port in http_ports {
+        let metrics_port = config.metrics_port;
+        let health_port = config.health_port;
+        let api_port = config.http_api_port;
+        let api = api.clone();
+        let handler: http::Handler = Arc::new(move |request| {
+            let api = api.clone();
+            Box::pin(async move {
+                match (request.method.as_str(), request.path.as_str()) {
+                    ("GET", "/metrics") if metrics_port == Some(port) => Some(metrics::response()),
+                    ("GET", "/health") if health_port == Some(port) => {
+                        Some(health::response(max_response_age))
+                    }
+                    _ if api_port == Some(port) => api.handle(request).await,
+                    _ => None,
+                }
+            })
+        });
+        tokio::spawn(http::serve(port, handler));
+    }
+
+    for (device_config, device) in config.devices.iter().zip(&devices) {
+        if config.poll_interval > 0 {
+            tokio::spawn(poller::poll_state(
+                client.clone(),
+                device.clone(),
+                config.clone(),
+                device_config.clone(),
+            ));
+        }
+
+        if config.device_poll_interval > 0 {
+            tokio::spawn(poller::poll_reachability(
+                client.clone(),
+                device.clone(),
+                device_config.device_availability_topic(),
+                Duration::from_secs(config.device_poll_interval),
+            ));
+        }
+
+        if config.time_sync_interval > 0 {
+            tokio::spawn(clock::sync_periodically(
+                device.clone(),
+                config.timezone,
+                Duration::from_secs(config.time_sync_interval),
+            ));
+        }
+
+        if config.clock_poll_interval > 0 {
+            tokio::spawn(clock::monitor_drift(
+                client.clone(),
+                device.clone(),
+                config.clone(),
+                device_config.clone(),
+            ));
+        }
+
+        if config.card_count_poll_interval > 0 {
+            tokio::spawn(cards::monitor_count(
+                client.clone(),
+                device.clone(),
+                config.clone(),
+                device_config.clone(),
+            ));
+        }
+    }
+
+    if let Some(address) = config.event_listener {
+        if config.legacy_event_topic {
+            warn!(
+                "Events are also published to {}/event, which is deprecated. Subscribe to \
+                 {0}/door/<door>/event and {0}/system/event instead, and set \
+                 'legacy_event_topic' to false",
+                config.devices[0].base_topic(),
+            );
+        }
+        tokio::spawn(events::listen(
+            client.clone(),
+            uhppoted,
+            config.clone(),
+            devices.clone(),
+            address,
+        ));
+    }
+
+    // Number of consecutive failed connection attempts
+    let mut failures: u32 = 0;
+    // Number of consecutive failed connection attempts to the current broker
+    let mut broker_failures: u32 = 0;
+
+    // Whether we've been connected to a broker since starting
+    let mut started = false;
+    // When the current connection was established, and the connections in a row that were lost
+    // right after connecting
+    let mut connected_at = None;
+    let mut short_sessions: u32 = 0;
+
+    let mut signal = Box::pin(shutdown_signal());
+
+    // The broker may be starting at the same time, like in Docker Compose
+    if config.startup_delay_secs > 0 {
+        info!(
+            "Waiting {}s before connecting to the MQTT broker",
+            config.startup_delay_secs
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(config.startup_delay_secs)) => {}
+            _ = &mut signal => return Ok(()),
+        }
+    }
+    open_time::start(client.clone(), config.clone());
+    let mut state_file = config.state_file.clone().map(StateFile::load);
+    if let Some(state_file) = &state_file {
+        tokio::spawn(state::restore(
+            client.clone(),
+            config.clone(),
+            devices.clone(),
+            state_file.states(),
+        ));
+    }
+
+    let mut debouncer = Debouncer::new(Duration::from_millis(config.min_command_interval_ms));
+    let mut relocker = Relocker::default();
+    let watchdog_timeout = config.watchdog_timeout();
+    let max_age = Duration::from_secs(config.command_max_age_secs);
+    let mut verifier = config
+        .command_hmac_key
+        .as_deref()
+        .map(|key| Verifier::new(key, max_age));
+
+    loop {
+        let event = tokio::select! {
+            event = eventloop.poll() => event,
+            // The event loop returns at least every keep-alive interval for the ping, so one
+            // that doesn't return for longer is stuck without reporting an error
+            _ = tokio::time::sleep(watchdog_timeout), if !watchdog_timeout.is_zero() => {
+                error!(
+                    "No MQTT traffic for {}s, the connection seems to hang. Restarting the MQTT \
+                     event loop",
+                    watchdog_timeout.as_secs()
+                );
+                health::MQTT_CONNECTED.store(false, Ordering::Relaxed);
+                metrics::MQTT_RECONNECTS.inc();
+                eventloop = restart_eventloop(&eventloop);
+                continue;
+            }
+            (topic, payload) = debouncer.next() => {
+                if let Some((device, door)) = find_door(&config, &devices, &topic) {
+                    let result = handle_command(
+                        &client,
+                        &config,
+                        device,
+                        door,
+                        &payload,
+                        args.dry_run,
+                        state_file.as_mut(),
+                    )
+                    .await;
+                    audit::command("mqtt", door, &payload, result.as_ref());
+                    syslog::command("mqtt", device.id(), door, result.as_ref());
+                    relocker.update(door, result.and_then(|result| result.state));
+                }
+                continue;
+            }
+            topic = relocker.next() => {
+                if let Some((device, door)) = find_door(&config, &devices, &topic) {
+                    info!("Locking {} again", door.name);
+                    let result = handle_command(
+                        &client,
+                        &config,
+                        device,
+                        door,
+                        relock::COMMAND,
+                        args.dry_run,
+                        state_file.as_mut(),
+                    )
+                    .await;
+                    audit::command("relock", door, relock::COMMAND, result.as_ref());
+                    syslog::command("relock", device.id(), door, result.as_ref());
+                    relocker.update(door, result.and_then(|result| result.state));
+                }
+                continue;
+            }
+            Some(command) = api_commands.recv() => {
+                let result = match find_door(&config, &devices, &command.command_topic) {
+                    Some((device, door)) => {
+                        let result = handle_command(
+                            &client,
+                            &config,
+                            device,
+                            door,
+                            &command.payload,
+                            args.dry_run,
+                            state_file.as_mut(),
+                        )
+                        .await;
+                        audit::command("api", door, &command.payload, result.as_ref());
+                        syslog::command("api", device.id(), door, result.as_ref());
+                        relocker.update(door, result.as_ref().and_then(|result| result.state));
+                        result
+                    }
+                    None => None,
+                };
+                command.reply.send(result).ok();
+                continue;
+            }
+            _ = &mut signal => break,
+        };
+        match event {
+            Ok(Incoming(Packet::ConnAck(_))) => {
+                health::MQTT_CONNECTED.store(true, Ordering::Relaxed);
+                connected_at = Some(Instant::now());
+                state::forget_published();
+                let (host, port) = &brokers[broker];
+                info!("Connected to MQTT broker {}:{}", host, port);
+                let mqtt_state = MqttState {
+                    state: "connected",
+                    broker: format!("{}:{}", host, port),
+                    failed_attempts: failures,
+                };
+                broker_failures = 0;
+                if !started {
+                    info!("Startup complete");
+                    started = true;
+                } else if failures > 0 {
+                    info!("Reconnected after {} failed attempts", failures);
+                }
+                failures = 0;
+
+                // Subscriptions don't survive a reconnect, so (re)announce on every connect. This
+                // happens in a separate task, since the requests are only sent while we poll.
+                let client = client.clone();
+                let config = config.clone();
+                let infos = infos.clone();
+                let versions = versions.clone();
+                tokio::spawn(async move {
+                    if config.publish_mqtt_state {
+                        let payload = serde_json::to_string(&mqtt_state).unwrap();
+                        let topic = config.mqtt_state_topic();
+                        info!("Publishing {} to {}", &payload, &topic);
+                        client
+                            .publish(&topic, QoS::AtLeastOnce, false, payload)
+                            .await
+                            .unwrap();
+                    }
+                    announce(&client, &config, &infos, &versions).await;
+                });
+            }
+            Ok(Incoming(Packet::Publish(p))) if p.topic == config.birth_topic => {
+                // A retained birth message is delivered when subscribing, right after we've
+                // announced ourselves, so only act on live ones
+                let came_online = !p.retain && &p.payload[..] == b"online";
+                if came_online {
+                    info!("Home Assistant came online, publishing discovery");
+                    versions.lock().unwrap().force();
+                    let client = client.clone();
+                    let config = config.clone();
+                    let infos = infos.clone();
+                    let versions = versions.clone();
+                    tokio::spawn(async move {
+                        publish_discovery(&client, &config, &infos, &versions).await;
+                    });
+                }
+            }
+            Ok(Incoming(Packet::Publish(mut p))) => {
+                if let Some(verifier) = &mut verifier {
+                    match verifier.verify(&p.topic, &p.payload) {
+                        Ok(payload) => p.payload = payload.into_bytes().into(),
+                        Err(e) => {
+                            metrics::COMMANDS_REJECTED.inc();
+                            warn!("Ignoring message on {}: {}", p.topic, e);
+                            audit::rejected(&p.topic, &p.payload, &format!("{:#}", e));
+                            continue;
+                        }
+                    }
+                }
+
+                let mut handled = false;
+                for (device_config, device) in config.devices.iter().zip(&devices) {
+                    if handle_device_request(&client, &config, device_config, device, &p).await {
+                        handled = true;
+                        break;
+                    }
+                }
+                if handled {
+                    continue;
+                }
+
+                if let Some((device, door)) = find_button(&config, &devices, &p.topic) {
+                    handle_button(
+                        &client,
+                        &config,
+                        device,
+                        door,
+                        &p,
+                        args.dry_run,
+                        state_file.as_mut(),
+                    )
+                    .await;
+                    continue;
+                }
+
+                if p.topic == config.all_command_topic() {
+                    handle_all_command(
+                        &client,
+                        &config,
+                        &devices,
+                        &p.payload,
+                        args.dry_run,
+                        state_file.as_mut(),
+                    )
+                    .await;
+                    // Relocking would take doors out of lockdown, or lock them during an
+                    // evacuation
+                    relocker.cancel_all();
+                    continue;
+                }
+
+                let (device, door) = match find_door(&config, &devices, &p.topic) {
+                    Some(target) => target,
+                    // Other doors under the same parent topic also match the wildcard
+                    None if config
+                        .command_filters()
+                        .iter()
+                        .any(|filter| matches_filter(filter, &p.topic)) =>
+                    {
+                        debug!("Ignoring command for unknown door on {}", p.topic);
+                        continue;
+                    }
+                    None => {
+                        warn!("Received message on unknown topic {}", p.topic);
+                        continue;
+                    }
+                };
+
+                let payload = match (&config.command_template, std::str::from_utf8(&p.payload)) {
+                    (Some(template), Ok(payload)) => {
+                        match template::command(template, device.id(), door, payload) {
+                            Some(command) => command.as_bytes().to_vec(),
+                            None => {
+                                let error = UnknownCommand(payload.to_string()).into();
+                                let result = publish_outcome(
+                                    &client,
+                                    &config,
+                                    door,
+                                    &p.payload,
+                                    Err(error),
+                                    None,
+                                )
+                                .await;
+                                audit::command("mqtt", door, &p.payload, result.as_ref());
+                                syslog::command("mqtt", device.id(), door, result.as_ref());
+                                continue;
+                            }
+                        }
+                    }
+                    // Invalid UTF-8 is logged and ignored when the command is handled
+                    _ => p.payload.to_vec(),
+                };
+                if let Some(payload) = debouncer.submit(&p.topic, payload) {
+                    let result = handle_command(
+                        &client,
+                        &config,
+                        device,
+                        door,
+                        &payload,
+                        args.dry_run,
+                        state_file.as_mut(),
+                    )
+                    .await;
+                    audit::command("mqtt", door, &payload, result.as_ref());
+                    syslog::command("mqtt", device.id(), door, result.as_ref());
+                    relocker.update(door, result.and_then(|result| result.state));
+                }
+            }
+            Ok(Incoming(Packet::Disconnect)) => {
+                info!("Disconnected by the MQTT broker");
+                health::MQTT_CONNECTED.store(false, Ordering::Relaxed);
+            }
+            Ok(Incoming(Packet::SubAck(ack))) => {
+                debug!("Subscription {} acknowledged", ack.pkid);
+            }
+            // Pings happen every keep-alive interval, so only log them when asked to
+            Ok(Event::Outgoing(Outgoing::PingReq)) => debug!("Sending MQTT ping"),
+            Ok(Incoming(Packet::PingResp)) => debug!("Received MQTT ping response"),
+            Err(err) => {
+                if health::MQTT_CONNECTED.swap(false, Ordering::Relaxed) {
+                    let (host, port) = &brokers[broker];
+                    info!("Lost connection to MQTT broker {}:{}", host, port);
+                }
+                if let Some(at) = connected_at.take() {
+                    if Instant::now() - at < SHORT_SESSION {
+                        short_sessions += 1;
+                    } else {
+                        short_sessions = 0;
+                    }
+                    if short_sessions == SHORT_SESSIONS {
+                        warn!(
+                            "The MQTT broker keeps closing the connection right after connecting. \
+                             Another client may be using the client id '{}', set a unique \
+                             'mqtt_id'",
+                            client_id
+                        );
+                    }
+                }
+                failures += 1;
+                // Waiting for the broker to come up isn't reconnecting
+                let waiting = !started && config.wait_for_broker;
+                // Credentials the broker rejects won't work any better on the next attempt
+                if let ConnectionError::ConnectionRefused(
+                    code @ (ConnectReturnCode::BadUserNamePassword
+                    | ConnectReturnCode::NotAuthorized),
+                ) = err
+                {
+                    if !waiting {
+                        shutdown(&client, &mut eventloop, &config).await;
+                        return Err(BridgeError::Config(anyhow!(
+                            "The MQTT broker refused the connection: {:?}",
+                            code
+                        ))
+                        .into());
+                    }
+                }
+                if !waiting
+                    && config.max_reconnect_attempts > 0
+                    && failures > config.max_reconnect_attempts
+                {
+                    error!(
+                        "Giving up after {} failed attempts to connect to the MQTT broker: {}",
+                        failures, err
+                    );
+                    shutdown(&client, &mut eventloop, &config).await;
+                    return Err(
+                        BridgeError::Mqtt(anyhow!("Failed to connect to the MQTT broker")).into(),
+                    );
+                }
+                if !waiting {
+                    metrics::MQTT_RECONNECTS.inc();
+                }
+
+                broker_failures += 1;
+                if brokers.len() > 1 && broker_failures >= FAILOVER_ATTEMPTS {
+                    broker = (broker + 1) % brokers.len();
+                    broker_failures = 0;
+                    let (host, port) = &brokers[broker];
+                    warn!(
+                        "MQTT connection error: {}. Failing over to {}:{}",
+                        err, host, port
+                    );
+                    // Subscriptions and discovery follow from the ConnAck, like on a reconnect
+                    eventloop.options = broker_options[broker].clone();
+                    continue;
+                }
+
+                let backoff = Duration::from_secs(2u64.pow(failures.min(7) - 1).min(MAX_BACKOFF));
+                if waiting {
+                    let (host, port) = &brokers[broker];
+                    info!(
+                        "Waiting for MQTT broker {}:{} to become ready ({}). Retrying in {}s",
+                        host,
+                        port,
+                        err,
+                        backoff.as_secs()
+                    );
+                } else {
+                    warn!(
+                        "MQTT connection error (attempt {}): {}. Retrying in {}s",
+                        failures,
+                        err,
+                        backoff.as_secs()
+                    );
+                }
+                tokio::time::sleep(backoff).await;
+            }
+            _ => {}
+        }
+    }
+
+    shutdown(&client, &mut eventloop, &config).await;
+    Ok(())
+}
+
+/// New event loop in place of `eventloop`, which connects again on the next poll. It takes its
+/// requests from the same channel, so the clients handed out to background tasks keep working.
+fn restart_eventloop(eventloop: &EventLoop) -> EventLoop {
+    let mut restarted = EventLoop::new(eventloop.options.clone(), 10);
+    restarted.requests_tx = eventloop.requests_tx.clone();
+    restarted.requests_rx = eventloop.requests_rx.clone();
+    restarted
+}
+
+/// Options for connecting to the broker at `mqtt_host` and `mqtt_port`.
+async fn mqtt_options(
+    config: &Config,
+    mqtt_host: &str,
+    mqtt_port: u16,
+    mqtt_ssl: bool,
+) -> Result<MqttOptions> {
+    let address =
+        if let MqttTransport::Websocket | MqttTransport::WebsocketTls = config.mqtt_transport {
+            // WebSocket brokers are addressed by URL, which keeps the host name for reverse proxies
+            format!(
+                "{}://{}:{}{}",
+                if mqtt_ssl { "wss" } else { "ws" },
+                mqtt_host,
+                mqtt_port,
+                config.mqtt_websocket_path
+            )
+        } else if mqtt_ssl {
+            // The broker certificate is verified against the host name, so we can't connect by IP
+            mqtt_host.to_string()
+        } else {
+            // Lookup MQTT host seperately, since we don't want to use the rust resolver on Docker
+            let resolver = AsyncResolver::tokio_from_system_conf()
+                .map_err(|e| BridgeError::Config(e.into()))?;
+            let response = resolver
+                .lookup_ip(mqtt_host)
+                .await
+                .map_err(|e| BridgeError::Network(e.into()))?;
+            response
+                .iter()
+                .next()
+                .expect("No address returned")
+                .to_string()
+        };
+
+    let mut mqttoptions = MqttOptions::new(config.mqtt_client_id(), address, mqtt_port);
+    mqttoptions.set_keep_alive(Duration::from_secs(config.mqtt_keepalive_secs));
+    match config.mqtt_transport {
+        MqttTransport::Tcp if mqtt_ssl => {
+            info!("Using TLS for MQTT");
+            let tls_config = tls_config(config).map_err(BridgeError::Config)?;
+            mqttoptions.set_transport(Transport::tls_with_config(tls_config.into()));
+        }
+        MqttTransport::Tcp => {}
+        MqttTransport::Websocket => {
+            info!("Using WebSocket for MQTT");
+            mqttoptions.set_transport(Transport::ws());
+        }
+        MqttTransport::WebsocketTls => {
+            info!("Using WebSocket with TLS for MQTT");
+            let tls_config = tls_config(config).map_err(BridgeError::Config)?;
+            mqttoptions.set_transport(Transport::wss_with_config(tls_config.into()));
+        }
+    }
+    // With a client certificate, the broker may not need a username and password
+    match (&config.mqtt_username, &config.mqtt_password) {
+        (Some(username), Some(password)) => {
+            mqttoptions.set_credentials(username, password);
+        }
+        (Some(username), None) if config.mqtt_client_cert.is_some() => {
+            mqttoptions.set_credentials(username, "");
+        }
+        (None, None) if config.mqtt_client_cert.is_some() => {}
+        (None, _) => return Err(BridgeError::Config(anyhow!("No MQTT username found")).into()),
+        (_, None) => return Err(BridgeError::Config(anyhow!("No MQTT password found")).into()),
+    }
+
+    mqttoptions.set_last_will(LastWill::new(
+        config.availability_topic(),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+
+    Ok(mqttoptions)
+}
+
+/// Get the MQTT connection details from the Home Assistant supervisor.
+async fn hass_mqtt_config() -> Result<HassResult> {
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://supervisor/services/mqtt")
+        .header(
+            "Authorization",
+            format!("Bearer {}", std::env::var("SUPERVISOR_TOKEN").unwrap()),
+        )
+        .send()
+        .await?;
+
+    match response.status() {
+        reqwest::StatusCode::OK => Ok(response.json::<HassResult>().await?),
+        _ => bail!("Failed to get MQTT config from HASS: {}", response.status()),
+    }
+}
+
+/// Override the config with environment variables, so secrets can be kept out of the config file.
+fn apply_env(config: &mut Config) -> Result<()> {
+    if let Ok(host) = std::env::var("MQTT_HOST") {
+        config.mqtt_host = vec![host];
+    }
+    if let Ok(port) = std::env::var("MQTT_PORT") {
+        config.mqtt_port = vec![port
+            .parse()
+            .with_context(|| format!("Invalid MQTT_PORT '{}'", port))?];
+    }
+    if let Ok(username) = std::env::var("MQTT_USERNAME") {
+        config.mqtt_username = Some(username);
+    }
+    if let Ok(password) = std::env::var("MQTT_PASSWORD") {
+        config.mqtt_password = Some(password);
+    }
+    if let Ok(token) = std::env::var("HTTP_API_TOKEN") {
+        config.http_api_token = Some(token);
+    }
+    if let Ok(key) = std::env::var("COMMAND_HMAC_KEY") {
+        config.command_hmac_key = Some(key);
+    }
+    Ok(())
+}
+
+/// Whether to use TLS for MQTT, as set by `mqtt_ssl` and `mqtt_transport`.
+fn mqtt_ssl(config: &Config) -> Result<bool> {
+    match (config.mqtt_transport, config.mqtt_ssl) {
+        (MqttTransport::Websocket, Some(true)) => {
+            bail!("Use 'mqtt_transport' websocket_tls for WebSockets with TLS")
+        }
+        (MqttTransport::WebsocketTls, Some(false)) => {
+            bail!("'mqtt_transport' websocket_tls requires TLS, but 'mqtt_ssl' is false")
+        }
+        (MqttTransport::WebsocketTls, _) => Ok(true),
+        (MqttTransport::Tcp | MqttTransport::Websocket, ssl) => Ok(ssl.unwrap_or(false)),
+    }
+}
+
+/// Check the config for `--check` and print what it contains. Loading the config already
+/// validated most of it, this makes sure the MQTT connection details are complete too.
+fn check(path: &str, config: &Config) -> Result<()> {
+    // Under Home Assistant the connection details come from the supervisor
+    if std::env::var("SUPERVISOR_TOKEN").is_err() {
+        let credentials = config.mqtt_client_cert.is_none();
+        let missing = [
+            ("mqtt_host", config.mqtt_host.is_empty()),
+            ("mqtt_port", config.mqtt_port.is_empty()),
+            (
+                "mqtt_username",
+                credentials && config.mqtt_username.is_none(),
+            ),
+            (
+                "mqtt_password",
+                credentials && config.mqtt_password.is_none(),
+            ),
+        ]
+        .iter()
+        .filter(|(_, missing)| *missing)
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            bail!("Missing MQTT settings: {}", missing.join(", "));
+        }
+    }
+    if mqtt_ssl(config)? {
+        tls_config(config)?;
+    } else if config.mqtt_client_cert.is_some() {
+        warn!("'mqtt_client_cert' is ignored without TLS");
+    }
+
+    match path {
+        "-" => println!("Config from stdin is valid"),
+        path => println!("Config {} is valid", path),
+    }
+    println!("MQTT client id {}", config.mqtt_client_id());
+    for device in &config.devices {
+        match &device.uhppote_device_ip {
+            Some(ip) => println!("Device {} at {}", device.uhppote_device_id, ip),
+            None => println!("Device {} found by broadcast", device.uhppote_device_id),
+        }
+        for door in &device.doors {
+            println!(
+                "  Door {} '{}' on {}",
+                door.door, door.name, door.base_topic
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Wait for SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = terminate.recv() => {},
+        _ = tokio::signal::ctrl_c() => {},
+    }
+}
+
+/// Mark the bridge as offline and disconnect from the broker. With `clear_discovery_on_exit`,
+/// the discovery configs are removed too, so the entities disappear from Home Assistant.
+async fn shutdown(client: &AsyncClient, eventloop: &mut EventLoop, config: &Config) {
+    info!("Shutting down");
+
+    // Requests are only sent while the event loop is polled, so queue them from another task
+    let client = client.clone();
+    let topic = config.availability_topic();
+    let discovery_topics = if config.clear_discovery_on_exit {
+        discovery::topics(config)
+    } else {
+        Vec::new()
+    };
+    let discovery_qos = config.discovery_qos;
+    tokio::spawn(async move {
+        for discovery_topic in discovery_topics {
+            info!("Removing discovery config {}", &discovery_topic);
+            client
+                .publish(&discovery_topic, discovery_qos, true, "")
+                .await
+                .ok();
+        }
+        info!("Publishing offline to {}", &topic);
+        client
+            .publish(&topic, QoS::AtLeastOnce, true, "offline")
+            .await
+            .ok();
+        client.disconnect().await.ok();
+    });
+
+    // Wait for the broker to close the connection. Closing it ourselves right after sending the
+    // disconnect can make the broker drop the last publishes.
+    let mut disconnect_sent = false;
+    let closed = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => disconnect_sent = true,
+                Err(_) => break,
+                _ => {}
+            }
+        }
+    })
+    .await;
+
+    if closed.is_err() && !disconnect_sent {
+        warn!("Timed out disconnecting from MQTT broker");
+    }
+}
+
+/// Find the door with `command_topic` and the device it belongs to.
+fn find_door<'a>(
+    config: &'a Config,
+    devices: &'a [Arc<DeviceHandle>],
+    command_topic: &str,
+) -> Option<(&'a Arc<DeviceHandle>, &'a DoorConfig)> {
+    config
+        .devices
+        .iter()
+        .zip(devices)
+        .find_map(|(device_config, device)| {
+            device_config
+                .doors
+                .iter()
+                .find(|d| d.command_topic() == command_topic)
+                .map(|door| (device, door))
+        })
+}
+
+/// Find the door with the button topic `topic` and the device it belongs to.
+fn find_button<'a>(
+    config: &'a Config,
+    devices: &'a [Arc<DeviceHandle>],
+    topic: &str,
+) -> Option<(&'a Arc<DeviceHandle>, &'a DoorConfig)> {
+    config
+        .devices
+        .iter()
+        .zip(devices)
+        .find_map(|(device_config, device)| {
+            device_config
+                .doors
+                .iter()
+                .find(|d| device_config.button_topic(d.door) == topic)
+                .map(|door| (device, door))
+        })
+}
+
+/// Run `f` with `device` on a blocking thread. Device requests wait for the answer over UDP,
+/// which takes a while with retries, and shouldn't hold up the runtime.
+async fn on_device<T: Send + 'static>(
+    device: &Arc<DeviceHandle>,
+    f: impl FnOnce(&DeviceHandle) -> T + Send + 'static,
+) -> T {
+    let device = device.clone();
+    tokio::task::spawn_blocking(move || f(&device))
+        .await
+        .unwrap()
+}
+
+/// Handle a request on one of the topics of `device` rather than one of its doors, and publish
+/// the outcome. Returns false when the topic of `publish` isn't one of them.
+async fn handle_device_request(
+    client: &AsyncClient,
+    config: &Config,
+    device_config: &DeviceConfig,
+    device: &Arc<DeviceHandle>,
+    publish: &Publish,
+) -> bool {
+    let topic = &publish.topic;
+    let payload = publish.payload.clone();
+    let (payload, result_topic) = if *topic == device_config.cards_get_topic() {
+        // Reading thousands of cards takes a while, so don't hold up other messages
+        tokio::spawn(list_cards(
+            client.clone(),
+            device_config.clone(),
+            device.clone(),
+        ));
+        return true;
+    } else if *topic == device_config.status_get_topic() {
+        let result = on_device(device, |device| status::handle(device)).await;
+        (Ok(result), device_config.status_topic())
+    } else if *topic == device_config.card_set_topic() {
+        let result = on_device(device, move |device| cards::handle_set(device, &payload)).await;
+        (
+            serde_json::to_string(&result),
+            device_config.card_result_topic(),
+        )
+    } else if *topic == device_config.card_delete_topic() {
+        let result = on_device(device, move |device| cards::handle_delete(device, &payload)).await;
+        (
+            serde_json::to_string(&result),
+            device_config.card_result_topic(),
+        )
+    } else if *topic == device_config.card_privilege_topic() {
+        let doors = device_config.doors.clone();
+        let result = on_device(device, move |device| {
+            cards::handle_privilege(device, &doors, &payload)
+        })
+        .await;
+        (
+            serde_json::to_string(&result),
+            device_config.card_result_topic(),
+        )
+    } else if config.allow_network_config && *topic == device_config.network_set_topic() {
+        let result = on_device(device, move |device| network::handle_set(device, &payload)).await;
+        (
+            serde_json::to_string(&result),
+            device_config.network_result_topic(),
+        )
+    } else if config.enable_raw && *topic == device_config.raw_topic() {
+        let result = on_device(device, move |device| raw::handle(device, &payload)).await;
+        (
+            serde_json::to_string(&result),
+            device_config.raw_result_topic(),
+        )
+    } else {
+        return false;
+    };
+
+    let payload = payload.unwrap();
+    audit::request(topic, &publish.payload, &payload);
+    info!("Publishing {} to {}", &payload, &result_topic);
+    client
+        .publish(&result_topic, QoS::AtLeastOnce, false, payload)
+        .await
+        .unwrap();
+    true
+}
+
+/// Read all cards from `device` and publish them in chunks to the card list topic.
+async fn list_cards(client: AsyncClient, device_config: DeviceConfig, device: Arc<DeviceHandle>) {
+    let result = tokio::task::spawn_blocking(move || cards::list(&device))
+        .await
+        .unwrap();
+
+    match result {
+        Ok(chunks) => {
+            let list_topic = device_config.cards_list_topic();
+            for chunk in chunks {
+                let payload = serde_json::to_string(&chunk).unwrap();
+                debug!("Publishing {} to {}", &payload, &list_topic);
+                client
+                    .publish(&list_topic, QoS::AtLeastOnce, false, payload)
+                    .await
+                    .unwrap();
+            }
+        }
+        Err(e) => {
+            metrics::DEVICE_ERRORS.inc();
+            let payload =
+                serde_json::to_string(&cards::CardResult::new("get", None, Err(e))).unwrap();
+            let result_topic = device_config.card_result_topic();
+            info!("Publishing {} to {}", &payload, &result_topic);
+            client
+                .publish(&result_topic, QoS::AtLeastOnce, false, payload)
+                .await
+                .unwrap();
+        }
+    }
+}
+
+/// Send the command in `payload` to `door` and publish the outcome. Returns the outcome, or
+/// `None` if the payload was ignored.
+async fn handle_command(
+    client: &AsyncClient,
+    config: &Arc<Config>,
+    device: &Arc<DeviceHandle>,
+    door: &DoorConfig,
+    payload: &[u8],
+    dry_run: bool,
+    state_file: Option<&mut StateFile>,
+) -> Option<CommandResult> {
+    let delay = Duration::from_secs(config.open_delay.into());
+    let result = {
+        let config = config.clone();
+        let door = door.clone();
+        let payload = payload.to_vec();
+        on_device(device, move |device| {
+            device.track(handle_payload(
+                device,
+                &door,
+                delay,
+                config.enforce_delay,
+                &payload,
+                &config.aliases,
+                dry_run,
+            ))
+        })
+        .await
+    };
+    publish_outcome(client, config, door, payload, result, state_file).await
+}
+
+/// Enable or disable the exit button of `door` with the command in `publish`, and publish the
+/// outcome to the result topic of the door.
+async fn handle_button(
+    client: &AsyncClient,
+    config: &Arc<Config>,
+    device: &Arc<DeviceHandle>,
+    door: &DoorConfig,
+    publish: &Publish,
+    dry_run: bool,
+    state_file: Option<&mut StateFile>,
+) {
+    let result = {
+        let number = door.door;
+        let payload = publish.payload.to_vec();
+        on_device(device, move |device| {
+            device.track(button::handle(device, number, &payload, dry_run))
+        })
+        .await
+    };
+    let outcome = publish_outcome(client, config, door, &publish.payload, result, state_file).await;
+    if let Some(outcome) = outcome {
+        let response = serde_json::to_string(&outcome).unwrap();
+        audit::request(&publish.topic, &publish.payload, &response);
+    }
+}
+
+/// Publish the outcome of the command in `payload` for `door`, and save the state it ended up
+/// in. Returns the outcome, or `None` if the payload was ignored.
+async fn publish_outcome(
+    client: &AsyncClient,
+    config: &Arc<Config>,
+    door: &DoorConfig,
+    payload: &[u8],
+    result: Result<Option<Transition>>,
+    state_file: Option<&mut StateFile>,
+) -> Option<CommandResult> {
+    if let (Some(state_file), Ok(Some(transition))) = (state_file, &result) {
+        // Save the state the door ends up in, an open door locks again after the delay
+        let state = transition.after.unwrap_or(transition.state);
+        state_file.set(&door.state_topic(), state, transition.delay.as_secs());
+    }
+
+    // Nothing happened, like for a command that isn't valid UTF-8
+    if let Ok(None) = result {
+        return None;
+    }
+
+    if let Some(UnknownCommand(command)) = result.as_ref().err().and_then(|e| e.downcast_ref()) {
+        match config.unknown_command_action {
+            UnknownCommandAction::Ignore => {
+                debug!("Ignoring unknown command '{}'", command);
+                return None;
+            }
+            UnknownCommandAction::Log => {
+                warn!(
+                    "Ignoring unknown command '{}' on {}",
+                    command,
+                    door.command_topic()
+                );
+                return None;
+            }
+            UnknownCommandAction::Report => {
+                let error_topic = door.error_topic();
+                info!("Publishing {} to {}", command, &error_topic);
+                client
+                    .publish(&error_topic, QoS::AtLeastOnce, false, payload)
+                    .await
+                    .unwrap();
+            }
+        }
+    }
+
+    let command_result = CommandResult {
+        command: String::from_utf8_lossy(payload).into_owned(),
+        success: result.is_ok(),
+        state: match &result {
+            Ok(Some(transition)) => Some(transition.state),
+            _ => None,
+        },
+        error: result.as_ref().err().map(|e| e.to_string()),
+    };
+    let result_payload = serde_json::to_string(&command_result).unwrap();
+    let result_topic = door.result_topic();
+    info!("Publishing {} to {}", &result_payload, &result_topic);
+    client
+        .publish(&result_topic, QoS::AtLeastOnce, false, result_payload)
+        .await
+        .unwrap();
+
+    match result {
+        Ok(Some(transition)) => {
+            let state_topic = door.state_topic();
+            let payload = state::payload(
+                config,
+                &state_topic,
+                transition.state,
+                Some(transition.delay),
+            );
+            if state::should_publish(config, &state_topic, &payload) {
+                info!("Publishing {} to {}", &payload, &state_topic);
+                audit::state(&state_topic, &payload);
+                open_time::state(&state_topic, transition.state);
+                client
+                    .publish(&state_topic, config.state_qos, config.retain_state, payload)
+                    .await
+                    .unwrap();
+            }
+
+            // Publish the state the door returns to once the delay has passed
+            if let Some(after) = transition.after {
+                let client = client.clone();
+                let config = config.clone();
+                let delay = transition.delay;
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let payload = state::payload(&config, &state_topic, after, Some(delay));
+                    if !state::should_publish(&config, &state_topic, &payload) {
+                        return;
+                    }
+                    info!("Publishing {} to {}", &payload, &state_topic);
+                    audit::state(&state_topic, &payload);
+                    open_time::state(&state_topic, after);
+                    client
+                        .publish(&state_topic, config.state_qos, config.retain_state, payload)
+                        .await
+                        .unwrap();
+                });
+            }
+        }
+        Ok(None) => {}
+        Err(e) => {
+            error!("{}", e);
+        }
+    }
+
+    Some(command_result)
+}
+
+/// Set every door of every device to the mode of the emergency command in `payload`, like
+/// `LOCKDOWN` or `EVACUATE`. The outcome is published for each door, and for all doors together
+/// to the all doors result topic.
+async fn handle_all_command(
+    client: &AsyncClient,
+    config: &Arc<Config>,
+    devices: &[Arc<DeviceHandle>],
+    payload: &[u8],
+    dry_run: bool,
+    mut state_file: Option<&mut StateFile>,
+) {
+    let command = String::from_utf8_lossy(payload).trim().to_string();
+    let mut all_result = AllCommandResult {
+        command,
+        success: true,
+        error: None,
+        doors: Vec::new(),
+    };
+
+    match Emergency::parse(&all_result.command) {
+        Some(emergency) => {
+            warn!("{} all doors", emergency.describe());
+            let delay = Duration::from_secs(config.open_delay.into());
+            for (device_config, device) in config.devices.iter().zip(devices) {
+                for door in &device_config.doors {
+                    let result = {
+                        let door = door.clone();
+                        on_device(device, move |device| {
+                            device.track(commands::handle_emergency(
+                                device, &door, delay, emergency, dry_run,
+                            ))
+                        })
+                        .await
+                    };
+                    let outcome = publish_outcome(
+                        client,
+                        config,
+                        door,
+                        all_result.command.as_bytes(),
+                        result,
+                        state_file.as_deref_mut(),
+                    )
+                    .await;
+                    audit::command("all", door, all_result.command.as_bytes(), outcome.as_ref());
+                    syslog::command("all", device.id(), door, outcome.as_ref());
+                    if let Some(outcome) = outcome {
+                        all_result.success &= outcome.success;
+                        all_result.doors.push(DoorResult {
+                            device: device_config.uhppote_device_id,
+                            door: door.door,
+                            name: door.name.clone(),
+                            success: outcome.success,
+                            error: outcome.error,
+                        });
+                    }
+                }
+            }
+        }
+        None => {
+            warn!("Unknown command '{}' for all doors", all_result.command);
+            all_result.success = false;
+            all_result.error = Some(format!("Unknown command '{}'", all_result.command));
+        }
+    }
+
+    let result_payload = serde_json::to_string(&all_result).unwrap();
+    let result_topic = config.all_result_topic();
+    info!("Publishing {} to {}", &result_payload, &result_topic);
+    client
+        .publish(&result_topic, QoS::AtLeastOnce, false, result_payload)
+        .await
+        .unwrap();
+}
+
+/// Subscribe to all topics we handle and publish the discovery configs.
+async fn announce(
+    client: &AsyncClient,
+    config: &Config,
+    infos: &[Option<ControllerInfo>],
+    versions: &Mutex<DiscoveryVersions>,
+) {
+    for device in &config.devices {
+        let mut topics = vec![
+            device.status_get_topic(),
+            device.cards_get_topic(),
+            device.card_set_topic(),
+            device.card_delete_topic(),
+            device.card_privilege_topic(),
+        ];
+        topics.extend(
+            device
+                .doors
+                .iter()
+                .map(|door| device.button_topic(door.door)),
+        );
+        if config.allow_network_config {
+            topics.push(device.network_set_topic());
+        }
+        if config.enable_raw {
+            topics.push(device.raw_topic());
+        }
+        for topic in topics {
+            info!("Subscribing to {}", topic);
+            client.subscribe(&topic, config.command_qos).await.unwrap();
+        }
+    }
+
+    for filter in config.command_filters() {
+        info!("Subscribing to {}", filter);
+        client.subscribe(&filter, config.command_qos).await.unwrap();
+    }
+    info!("Subscribing to {}", config.all_command_topic());
+    client
+        .subscribe(config.all_command_topic(), config.command_qos)
+        .await
+        .unwrap();
+
+    if config.enable_discovery {
+        info!("Subscribing to {}", config.birth_topic);
+        client
+            .subscribe(&config.birth_topic, QoS::AtLeastOnce)
+            .await
+            .unwrap();
+    }
+
+    publish_discovery(client, config, infos, versions).await;
+}
+
+/// Publish the discovery configs, if enabled, and device info, followed by our availability.
+/// Also done when Home Assistant comes back online, since it forgets entities that aren't
+/// retained.
+async fn publish_discovery(
+    client: &AsyncClient,
+    config: &Config,
+    infos: &[Option<ControllerInfo>],
+    versions: &Mutex<DiscoveryVersions>,
+) {
+    for (device, info) in config.devices.iter().zip(infos) {
+        if let Some(info) = info {
+            let payload = serde_json::to_string(info).unwrap();
+            let info_topic = device.info_topic();
+            info!("Publishing {} to {}", &payload, &info_topic);
+            client
+                .publish(&info_topic, QoS::AtLeastOnce, true, payload)
+                .await
+                .unwrap();
+        }
+        if !config.enable_discovery {
+            continue;
+        }
+
+        if config.device_poll_interval > 0 {
+            let payload =
+                serde_json::to_string(&discovery::Connectivity::new(config, device, info.as_ref()))
+                    .unwrap();
+            let config_topic = discovery::Connectivity::topic(config, device);
+            publish_config(client, config, versions, &config_topic, payload).await;
+        }
+
+        // Faults are read by the state poller
+        if config.poll_interval > 0 {
+            let payload =
+                serde_json::to_string(&discovery::Fault::new(config, device, info.as_ref()))
+                    .unwrap();
+            let config_topic = discovery::Fault::topic(config, device);
+            publish_config(client, config, versions, &config_topic, payload).await;
+        }
+
+        if config.clock_poll_interval > 0 {
+            let payload = serde_json::to_string(&discovery::ControllerTime::new(
+                config,
+                device,
+                info.as_ref(),
+            ))
+            .unwrap();
+            let config_topic = discovery::ControllerTime::topic(config, device);
+            publish_config(client, config, versions, &config_topic, payload).await;
+
+            let payload =
+                serde_json::to_string(&discovery::ClockDrift::new(config, device, info.as_ref()))
+                    .unwrap();
+            let config_topic = discovery::ClockDrift::topic(config, device);
+            publish_config(client, config, versions, &config_topic, payload).await;
+
+            let payload =
+                serde_json::to_string(&discovery::ClockProblem::new(config, device, info.as_ref()))
+                    .unwrap();
+            let config_topic = discovery::ClockProblem::topic(config, device);
+            publish_config(client, config, versions, &config_topic, payload).await;
+        }
+
+        if config.card_count_poll_interval > 0 {
+            let payload =
+                serde_json::to_string(&discovery::CardCount::new(config, device, info.as_ref()))
+                    .unwrap();
+            let config_topic = discovery::CardCount::topic(config, device);
+            publish_config(client, config, versions, &config_topic, payload).await;
+        }
+
+        // Events only come in through the listener
+        if config.event_listener.is_some() {
+            let payload =
+                serde_json::to_string(&discovery::LastEvent::new(config, device, info.as_ref()))
+                    .unwrap();
+            let config_topic = discovery::LastEvent::topic(config, device);
+            publish_config(client, config, versions, &config_topic, payload).await;
+        }
+
+        for door in &device.doors {
+            // Post to the discovery topic
+            let payload =
+                serde_json::to_string(&discovery::Lock::new(config, device, info.as_ref(), door))
+                    .unwrap();
+            let config_topic = discovery::Lock::topic(config, device, door);
+            publish_config(client, config, versions, &config_topic, payload).await;
+
+            // Remove the config from where older versions published it, so the lock doesn't
+            // show up twice
+            let legacy_config_topic = door.legacy_config_topic();
+            if legacy_config_topic != config_topic {
+                publish_config(
+                    client,
+                    config,
+                    versions,
+                    &legacy_config_topic,
+                    String::new(),
+                )
+                .await;
+            }
+
+            let payload = serde_json::to_string(&discovery::OpenButton::new(
+                config,
+                device,
+                info.as_ref(),
+                door,
+            ))
+            .unwrap();
+            let config_topic = discovery::OpenButton::topic(config, device, door);
+            publish_config(client, config, versions, &config_topic, payload).await;
+
+            let payload = serde_json::to_string(&discovery::DoorSensor::new(
+                config,
+                device,
+                info.as_ref(),
+                door,
+            ))
+            .unwrap();
+            let config_topic = discovery::DoorSensor::topic(config, device, door);
+            publish_config(client, config, versions, &config_topic, payload).await;
+        }
+    }
+
+    // Remove the entities that were removed from the config since the previous run
+    let stale = versions.lock().unwrap().finish();
+    for topic in stale {
+        info!("Removing discovery config {}", &topic);
+        client
+            .publish(&topic, config.discovery_qos, true, "")
+            .await
+            .unwrap();
+    }
+
+    let availability_topic = config.availability_topic();
+    info!("Publishing online to {}", &availability_topic);
+    client
+        .publish(&availability_topic, QoS::AtLeastOnce, true, "online")
+        .await
+        .unwrap();
+}
+
+/// Publish the discovery config `payload` to `topic`, unless it's unchanged since the previous
+/// run.
+async fn publish_config(
+    client: &AsyncClient,
+    config: &Config,
+    versions: &Mutex<DiscoveryVersions>,
+    topic: &str,
+    payload: String,
+) {
+    if !versions.lock().unwrap().update(topic, &payload) {
+        debug!("Discovery config {} is unchanged", topic);
+        return;
+    }
+    info!("Publishing {} to {}", &payload, topic);
+    client
+        .publish(topic, config.discovery_qos, true, payload)
+        .await
+        .unwrap();
+}
+
+/// Build the TLS client config for the MQTT connection.
+fn tls_config(config: &Config) -> Result<ClientConfig> {
+    let mut root_store = RootCertStore::empty();
+    match &config.mqtt_ca_cert {
+        Some(path) => {
+            for cert in read_certs(path)? {
+                root_store.add(&cert)?;
+            }
+        }
+        None => {
+            for cert in rustls_native_certs::load_native_certs()? {
+                root_store.add(&Certificate(cert.0))?;
+            }
+        }
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store);
+
+    let tls_config = match (&config.mqtt_client_cert, &config.mqtt_client_key) {
+        (Some(cert), Some(key)) => {
+            let certs = read_certs(cert)?;
+            let key = read_key(key)?;
+            check_key_matches(&certs[0], &key)?;
+            info!("Using client certificate for MQTT");
+            builder.with_single_cert(certs, key)?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => bail!("'mqtt_client_cert' and 'mqtt_client_key' must be specified together"),
+    };
+
+    Ok(tls_config)
+}
+
+/// Read all PEM encoded certificates from `path`.
+fn read_certs(path: &str) -> Result<Vec<Certificate>> {
+    let file = File::open(path).with_context(|| format!("Failed to read '{}'", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("Invalid certificate in '{}'", path))?;
+    if certs.is_empty() {
+        bail!("No certificates found in '{}'", path);
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Read the first PEM encoded PKCS8, RSA or EC private key from `path`.
+fn read_key(path: &str) -> Result<PrivateKey> {
+    let file = File::open(path).with_context(|| format!("Failed to read '{}'", path))?;
+    let mut reader = BufReader::new(file);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .with_context(|| format!("Invalid private key in '{}'", path))?
+        {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => bail!("No private key found in '{}'", path),
+        }
+    }
+}
+
+/// Check that `key` belongs to `cert` by signing with the key and verifying the signature with
+/// the certificate. Otherwise the broker just drops the connection during the handshake.
+fn check_key_matches(cert: &Certificate, key: &PrivateKey) -> Result<()> {
+    let algorithms = [
+        (
+            SignatureScheme::RSA_PKCS1_SHA256,
+            &webpki::RSA_PKCS1_2048_8192_SHA256,
+        ),
+        (
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            &webpki::ECDSA_P256_SHA256,
+        ),
+        (
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            &webpki::ECDSA_P384_SHA384,
+        ),
+        (SignatureScheme::ED25519, &webpki::ED25519),
+    ];
+
+    let key = rustls::sign::any_supported_type(key)
+        .map_err(|_| anyhow!("Unsupported type of 'mqtt_client_key'"))?;
+    let schemes = algorithms.map(|(scheme, _)| scheme);
+    let signer = match key.choose_scheme(&schemes) {
+        Some(signer) => signer,
+        None => bail!("Unsupported type of 'mqtt_client_key'"),
+    };
+    let algorithm = algorithms
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.scheme())
+        .map(|(_, algorithm)| *algorithm)
+        .unwrap();
+
+    let message = b"uhppote-mqtt";
+    let signature = signer.sign(message)?;
+    let cert = webpki::EndEntityCert::try_from(cert.0.as_slice())
+        .map_err(|e| anyhow!("Invalid 'mqtt_client_cert': {:?}", e))?;
+    if cert
+        .verify_signature(algorithm, message, &signature)
+        .is_err()
+    {
+        bail!("'mqtt_client_key' doesn't match 'mqtt_client_cert'");
+    }
+    Ok(())
+}
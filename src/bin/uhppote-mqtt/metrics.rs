@@ -0,0 +1,151 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::http::Response;
+
+/// Monotonically increasing metric.
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Metric holding a unix timestamp.
+pub struct Timestamp(AtomicU64);
+
+impl Timestamp {
+    pub const fn new() -> Self {
+        Timestamp(AtomicU64::new(0))
+    }
+
+    /// Set the timestamp to the current time.
+    pub fn set_now(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.0.store(now, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Seconds since the timestamp was set, or `None` if it never was.
+    pub fn age(&self) -> Option<u64> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        match self.get() {
+            0 => None,
+            then => Some(now.saturating_sub(then)),
+        }
+    }
+}
+
+pub static COMMANDS_LOCK: Counter = Counter::new();
+pub static COMMANDS_UNLOCK: Counter = Counter::new();
+pub static COMMANDS_OPEN: Counter = Counter::new();
+pub static COMMANDS_SECURE: Counter = Counter::new();
+pub static MQTT_RECONNECTS: Counter = Counter::new();
+pub static DEVICE_ERRORS: Counter = Counter::new();
+pub static EVENTS_DROPPED: Counter = Counter::new();
+pub static COMMANDS_REJECTED: Counter = Counter::new();
+pub static DOOR_OPEN_SECONDS: Counter = Counter::new();
+pub static LAST_SUCCESSFUL_POLL: Timestamp = Timestamp::new();
+
+/// Render all metrics in the Prometheus text format.
+fn render() -> String {
+    let mut out = String::new();
+
+    writeln!(
+        out,
+        "# HELP uhppote_commands_total Commands sent to devices."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE uhppote_commands_total counter").unwrap();
+    for (action, counter) in [
+        ("lock", &COMMANDS_LOCK),
+        ("unlock", &COMMANDS_UNLOCK),
+        ("open", &COMMANDS_OPEN),
+        ("secure", &COMMANDS_SECURE),
+    ] {
+        writeln!(
+            out,
+            "uhppote_commands_total{{action=\"{}\"}} {}",
+            action,
+            counter.get()
+        )
+        .unwrap();
+    }
+
+    for (name, help, kind, value) in [
+        (
+            "uhppote_mqtt_reconnects_total",
+            "Reconnection attempts to the MQTT broker.",
+            "counter",
+            MQTT_RECONNECTS.get(),
+        ),
+        (
+            "uhppote_device_errors_total",
+            "Failed device operations.",
+            "counter",
+            DEVICE_ERRORS.get(),
+        ),
+        (
+            "uhppote_events_dropped_total",
+            "Events dropped by the event rate limit.",
+            "counter",
+            EVENTS_DROPPED.get(),
+        ),
+        (
+            "uhppote_commands_rejected_total",
+            "MQTT requests ignored for a missing or invalid signature.",
+            "counter",
+            COMMANDS_REJECTED.get(),
+        ),
+        (
+            "uhppote_door_open_seconds_total",
+            "Seconds doors were unlocked or open, counted when they're locked again.",
+            "counter",
+            DOOR_OPEN_SECONDS.get(),
+        ),
+        (
+            "uhppote_last_successful_poll_timestamp_seconds",
+            "Unix time of the last successful device poll.",
+            "gauge",
+            LAST_SUCCESSFUL_POLL.get(),
+        ),
+    ] {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+        writeln!(out, "{} {}", name, value).unwrap();
+    }
+
+    out
+}
+
+/// Response for `GET /metrics`.
+pub fn response() -> Response {
+    Response {
+        status: "200 OK",
+        content_type: "text/plain; version=0.0.4",
+        body: render(),
+    }
+}
@@ -0,0 +1,83 @@
+use anyhow::{bail, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::net::Ipv4Addr;
+use uhppote_rs::Device;
+
+use crate::metrics;
+
+/// The controller doesn't confirm the change and moves to the new address right away. The
+/// bridge keeps talking to the configured address.
+const WARNING: &str = "The controller may need to be rediscovered, update 'uhppote_device_ip' \
+                       and restart the bridge if its address changed";
+
+/// Request on the network set topic, like
+/// `{"ip":"192.168.1.50","mask":"255.255.255.0","gateway":"192.168.1.1"}`.
+#[derive(Deserialize)]
+struct SetNetwork {
+    ip: Ipv4Addr,
+    mask: Ipv4Addr,
+    gateway: Ipv4Addr,
+}
+
+/// Outcome of a network request as published to the network result topic.
+#[derive(Serialize)]
+pub struct NetworkResult {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<&'static str>,
+}
+
+/// Set the IP address, netmask and gateway of the controller.
+pub fn handle_set(device: &Device, payload: &[u8]) -> NetworkResult {
+    let result = serde_json::from_slice::<SetNetwork>(payload)
+        .map_err(Into::into)
+        .and_then(|request| set_network(device, request));
+
+    match result {
+        Ok(()) => {
+            warn!("{}", WARNING);
+            NetworkResult {
+                success: true,
+                error: None,
+                warning: Some(WARNING),
+            }
+        }
+        Err(e) => NetworkResult {
+            success: false,
+            error: Some(e.to_string()),
+            warning: None,
+        },
+    }
+}
+
+fn set_network(device: &Device, request: SetNetwork) -> Result<()> {
+    if request.ip.is_unspecified() || request.ip.is_broadcast() || request.ip.is_multicast() {
+        bail!("Invalid IP address {}", request.ip);
+    }
+
+    // A netmask is a run of ones followed by zeroes
+    let mask = u32::from(request.mask);
+    if mask.leading_ones() + mask.trailing_zeros() != 32 {
+        bail!("Invalid netmask {}", request.mask);
+    }
+
+    if u32::from(request.gateway) & mask != u32::from(request.ip) & mask {
+        bail!(
+            "Gateway {} isn't in the subnet of {}/{}",
+            request.gateway,
+            request.ip,
+            request.mask
+        );
+    }
+
+    info!(
+        "Setting network to {}/{} with gateway {}",
+        request.ip, request.mask, request.gateway
+    );
+    device
+        .set_network_config(request.ip, request.mask, request.gateway)
+        .inspect_err(|_| metrics::DEVICE_ERRORS.inc())
+}
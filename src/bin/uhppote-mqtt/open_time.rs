@@ -0,0 +1,164 @@
+use chrono::{DateTime, Duration as ChronoDuration, Local, TimeZone, Utc};
+use chrono_tz::Tz;
+use log::debug;
+use rumqttc::AsyncClient;
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::commands::State;
+use crate::config::Config;
+use crate::metrics;
+
+/// States published to the state topics, and when they were published.
+static STATES: OnceLock<UnboundedSender<(String, State, DateTime<Utc>)>> = OnceLock::new();
+
+/// Door that's unlocked or open.
+struct Opened {
+    at: DateTime<Utc>,
+    // When the time counting towards today's total started, which is midnight for a door that
+    // was already open then
+    counting_from: DateTime<Utc>,
+}
+
+/// Topics to publish the open durations of a door to.
+struct Topics {
+    last: String,
+    today: String,
+}
+
+/// Start tracking how long doors stay unlocked or open, from the states published to their
+/// state topics. When a door is locked again, the time it was open is published to its last
+/// open duration topic and added to its total for today, which goes back to 0 at midnight.
+pub fn start(client: AsyncClient, config: Arc<Config>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    STATES.set(tx).expect("Open time tracking already started");
+    tokio::spawn(track(client, config, rx));
+}
+
+/// Record that `state` was published to `state_topic`.
+pub fn state(state_topic: &str, state: State) {
+    if let Some(states) = STATES.get() {
+        states
+            .send((state_topic.to_string(), state, Utc::now()))
+            .ok();
+    }
+}
+
+async fn track(
+    client: AsyncClient,
+    config: Arc<Config>,
+    mut rx: UnboundedReceiver<(String, State, DateTime<Utc>)>,
+) {
+    let mut topics = HashMap::new();
+    let mut today = HashMap::new();
+    for device in &config.devices {
+        for door in &device.doors {
+            topics.insert(
+                door.state_topic(),
+                Topics {
+                    last: device.last_open_duration_topic(door.door),
+                    today: device.open_duration_today_topic(door.door),
+                },
+            );
+            today.insert(door.state_topic(), 0);
+        }
+    }
+
+    let mut opened: HashMap<String, Opened> = HashMap::new();
+    loop {
+        let (state_topic, state, at) = tokio::select! {
+            update = rx.recv() => match update {
+                Some(update) => update,
+                None => return,
+            },
+            _ = tokio::time::sleep(until_midnight(config.timezone)) => {
+                let midnight = Utc::now();
+                for door in opened.values_mut() {
+                    door.counting_from = midnight;
+                }
+                for (state_topic, total) in today.iter_mut() {
+                    *total = 0;
+                    publish(&client, &config, &topics[state_topic].today, 0).await;
+                }
+                continue;
+            }
+        };
+        let door_topics = match topics.get(&state_topic) {
+            Some(door_topics) => door_topics,
+            None => continue,
+        };
+
+        match state {
+            State::Unlocked | State::Open => {
+                opened.entry(state_topic).or_insert(Opened {
+                    at,
+                    counting_from: at,
+                });
+            }
+            State::Locked | State::Secured => {
+                let door = match opened.remove(&state_topic) {
+                    Some(door) => door,
+                    None => continue,
+                };
+                let duration = seconds(at - door.at);
+                let total = today.entry(state_topic).or_insert(0);
+                *total += seconds(at - door.counting_from);
+                metrics::DOOR_OPEN_SECONDS.add(duration);
+                publish(&client, &config, &door_topics.last, duration).await;
+                publish(&client, &config, &door_topics.today, *total).await;
+            }
+        }
+    }
+}
+
+async fn publish(client: &AsyncClient, config: &Config, topic: &str, seconds: u64) {
+    debug!("Publishing {} to {}", seconds, topic);
+    client
+        .publish(
+            topic,
+            config.state_qos,
+            config.retain_state,
+            seconds.to_string(),
+        )
+        .await
+        .unwrap();
+}
+
+fn seconds(duration: ChronoDuration) -> u64 {
+    duration.num_seconds().max(0) as u64
+}
+
+/// Time until the next midnight in `timezone`, or in the host's timezone when it's not
+/// specified.
+fn until_midnight(timezone: Option<Tz>) -> Duration {
+    let now = Utc::now();
+    let midnight = match timezone {
+        Some(tz) => next_midnight(now.with_timezone(&tz)),
+        None => next_midnight(now.with_timezone(&Local)),
+    };
+    (midnight - now).to_std().unwrap_or_default()
+}
+
+fn next_midnight<T: TimeZone>(now: DateTime<T>) -> DateTime<Utc> {
+    let tomorrow = now
+        .date_naive()
+        .succ_opt()
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap();
+    // Midnight doesn't exist where the clocks go forward at midnight, the day starts an hour
+    // later then
+    let midnight = now
+        .timezone()
+        .from_local_datetime(&tomorrow)
+        .earliest()
+        .or_else(|| {
+            now.timezone()
+                .from_local_datetime(&(tomorrow + ChronoDuration::hours(1)))
+                .earliest()
+        })
+        .unwrap();
+    midnight.with_timezone(&Utc)
+}
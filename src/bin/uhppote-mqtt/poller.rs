@@ -0,0 +1,167 @@
+use log::{debug, error, warn};
+use rumqttc::{AsyncClient, QoS};
+use std::sync::Arc;
+use std::time::Duration;
+use uhppote_rs::Status;
+
+use crate::audit;
+use crate::button;
+use crate::commands::State;
+use crate::config::{Config, DeviceConfig, DoorConfig};
+use crate::events;
+use crate::fault;
+use crate::health;
+use crate::locate::DeviceHandle;
+use crate::metrics;
+use crate::open_time;
+use crate::retry::retry;
+use crate::state;
+
+/// Periodically read the door control state, door sensors and faults from the device and
+/// publish them, so the state reflects changes made outside of this bridge.
+pub async fn poll_state(
+    client: AsyncClient,
+    device: Arc<DeviceHandle>,
+    config: Arc<Config>,
+    device_config: DeviceConfig,
+) {
+    let doors = &device_config.doors;
+    let qos = config.state_qos;
+    let retain = config.retain_state;
+    let mut interval = tokio::time::interval(Duration::from_secs(config.poll_interval));
+    loop {
+        interval.tick().await;
+        for door in doors {
+            let device = device.clone();
+            let number = door.door;
+            let result = tokio::task::spawn_blocking(move || {
+                device.track(retry(|| device.get_door_control(number)))
+            })
+            .await
+            .unwrap();
+
+            match result {
+                Ok(control) => match State::from_mode(&control.mode) {
+                    Some(state) => {
+                        let state_topic = door.state_topic();
+                        let payload =
+                            state::payload(&config, &state_topic, state, Some(control.delay));
+                        if !state::should_publish(&config, &state_topic, &payload) {
+                            continue;
+                        }
+                        debug!("Publishing {} to {}", &payload, &state_topic);
+                        audit::state(&state_topic, &payload);
+                        open_time::state(&state_topic, state);
+                        client
+                            .publish(&state_topic, qos, retain, payload)
+                            .await
+                            .unwrap();
+                    }
+                    None => warn!("Door {} is in an unknown mode", number),
+                },
+                Err(e) => {
+                    metrics::DEVICE_ERRORS.inc();
+                    error!("Failed to get state of door {}: {}", number, e);
+                }
+            }
+        }
+
+        let status = {
+            let device = device.clone();
+            tokio::task::spawn_blocking(move || device.track(retry(|| device.get_status())))
+                .await
+                .unwrap()
+        };
+
+        match status {
+            Ok(status) => {
+                metrics::LAST_SUCCESSFUL_POLL.set_now();
+                health::LAST_DEVICE_RESPONSE.set_now();
+                for door in doors {
+                    let state = match door_open(door, &status) {
+                        Some(true) => "ON",
+                        Some(false) => "OFF",
+                        None => "unknown",
+                    };
+                    debug!("Publishing {} to {}", state, door.door_sensor_topic());
+                    client
+                        .publish(door.door_sensor_topic(), qos, false, state)
+                        .await
+                        .unwrap();
+
+                    // Only known once it was set, see `button::handle`
+                    let id = device_config.uhppote_device_id;
+                    if let Some(payload) = button::payload(id, door.door) {
+                        let topic = device_config.button_state_topic(door.door);
+                        debug!("Publishing {} to {}", payload, &topic);
+                        client.publish(&topic, qos, retain, payload).await.unwrap();
+                    }
+                }
+
+                if let Some(event) = &status.last_event {
+                    events::publish_index(&client, &config, &device_config, event.index).await;
+                }
+
+                let payload = fault::payload(&status);
+                let fault_topic = device_config.fault_topic();
+                debug!("Publishing {} to {}", &payload, &fault_topic);
+                client
+                    .publish(&fault_topic, qos, retain, payload)
+                    .await
+                    .unwrap();
+            }
+            Err(e) => {
+                metrics::DEVICE_ERRORS.inc();
+                error!("Failed to get device status: {}", e);
+            }
+        }
+    }
+}
+
+/// Whether `door` is open according to its sensor in `status`, or `None` if it has no sensor.
+pub fn door_open(door: &DoorConfig, status: &Status) -> Option<bool> {
+    if !door.door_sensor {
+        return None;
+    }
+    (door.door as usize)
+        .checked_sub(1)
+        .and_then(|i| status.doors.get(i))
+        .copied()
+}
+
+/// Periodically check whether the device responds and publish `online` or `offline`. Unlike the
+/// availability topic, which is about this bridge, this is about the controller itself.
+pub async fn poll_reachability(
+    client: AsyncClient,
+    device: Arc<DeviceHandle>,
+    topic: String,
+    interval: Duration,
+) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let device = device.clone();
+        let result =
+            tokio::task::spawn_blocking(move || device.track(retry(|| device.get_status())))
+                .await
+                .unwrap();
+
+        let availability = match result {
+            Ok(_) => {
+                health::LAST_DEVICE_RESPONSE.set_now();
+                "online"
+            }
+            Err(e) => {
+                metrics::DEVICE_ERRORS.inc();
+                warn!("Device is unreachable: {}", e);
+                "offline"
+            }
+        };
+
+        debug!("Publishing {} to {}", availability, topic);
+        client
+            .publish(&topic, QoS::AtLeastOnce, true, availability)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+use anyhow::Result;
+use log::info;
+use serde::{Deserialize, Serialize};
+use uhppote_rs::Device;
+
+use crate::metrics;
+use crate::retry::retry;
+
+/// Device operations that can be called on the raw topic. Only these are allowed, so a typo
+/// can't clear all cards or similar. Requests look like `{"operation":"get_event","index":1}`.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(tag = "operation", rename_all = "snake_case", deny_unknown_fields)]
+enum Operation {
+    GetCardById { card: u32 },
+    GetCardByIndex { index: u32 },
+    GetCards,
+    GetConfig,
+    GetDoorControl { door: u8 },
+    GetEvent { index: u32 },
+    GetEventIndex,
+    GetListener,
+    GetStatus,
+    GetTime,
+    GetTimeProfile { profile: u8 },
+    RecordSpecialEvents { enable: bool },
+    RefreshTaskList,
+    SetEventIndex { index: u32 },
+}
+
+/// Outcome of a raw request as published to the raw result topic. The response is the debug
+/// representation of what `uhppote-rs` returned.
+#[derive(Serialize)]
+pub struct RawResult {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+pub fn handle(device: &Device, payload: &[u8]) -> RawResult {
+    let result = serde_json::from_slice::<Operation>(payload)
+        .map_err(Into::into)
+        .and_then(|operation| {
+            info!("Calling {:?}", operation);
+            retry(|| call(device, operation)).inspect_err(|_| metrics::DEVICE_ERRORS.inc())
+        });
+
+    match result {
+        Ok(response) => RawResult {
+            success: true,
+            response: Some(response),
+            error: None,
+        },
+        Err(e) => RawResult {
+            success: false,
+            response: None,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+fn call(device: &Device, operation: Operation) -> Result<String> {
+    let response = match operation {
+        Operation::GetCardById { card } => format!("{:?}", device.get_card_by_id(card)?),
+        Operation::GetCardByIndex { index } => format!("{:?}", device.get_card_by_index(index)?),
+        Operation::GetCards => format!("{:?}", device.get_cards()?),
+        Operation::GetConfig => format!("{:?}", device.get_config()?),
+        Operation::GetDoorControl { door } => format!("{:?}", device.get_door_control(door)?),
+        Operation::GetEvent { index } => format!("{:?}", device.get_event(index)?),
+        Operation::GetEventIndex => format!("{:?}", device.get_event_index()?),
+        Operation::GetListener => format!("{:?}", device.get_listener()?),
+        Operation::GetStatus => format!("{:?}", device.get_status()?),
+        Operation::GetTime => format!("{:?}", device.get_time()?),
+        Operation::GetTimeProfile { profile } => {
+            format!("{:?}", device.get_time_profile(profile)?)
+        }
+        Operation::RecordSpecialEvents { enable } => {
+            format!("{:?}", device.enable_record_special_events(enable)?)
+        }
+        Operation::RefreshTaskList => format!("{:?}", device.refresh_task_list()?),
+        Operation::SetEventIndex { index } => format!("{:?}", device.set_event_index(index)?),
+    };
+    Ok(response)
+}
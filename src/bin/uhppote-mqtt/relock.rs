@@ -0,0 +1,60 @@
+use log::info;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::commands::State;
+use crate::config::DoorConfig;
+
+/// Command sent to a door when it's due to be locked again.
+pub const COMMAND: &[u8] = br#"{"action":"lock"}"#;
+
+/// Locks doors with `auto_relock_secs` again a while after they're unlocked, so a door doesn't
+/// stay unlocked when an automation forgets about it.
+#[derive(Default)]
+pub struct Relocker {
+    // When each unlocked door is due to be locked, by command topic
+    due: HashMap<String, Instant>,
+}
+
+impl Relocker {
+    /// Keep track of the `state` a command put `door` in. Unlocking starts the timer, or restarts
+    /// it when it was already running. Locking or securing the door cancels it.
+    pub fn update(&mut self, door: &DoorConfig, state: Option<State>) {
+        let secs = match door.auto_relock_secs {
+            Some(secs) => secs,
+            None => return,
+        };
+
+        match state {
+            Some(State::Unlocked) => {
+                info!("Locking {} again in {}s", door.name, secs);
+                self.due.insert(
+                    door.command_topic(),
+                    Instant::now() + Duration::from_secs(secs),
+                );
+            }
+            Some(State::Locked) | Some(State::Secured) => {
+                self.due.remove(&door.command_topic());
+            }
+            Some(State::Open) | None => {}
+        }
+    }
+
+    /// Forget about all doors that are due to be locked again.
+    pub fn cancel_all(&mut self) {
+        self.due.clear();
+    }
+
+    /// Wait until a door is due to be locked again, and return its command topic. Never
+    /// completes when no doors are waiting to be locked.
+    pub async fn next(&mut self) -> String {
+        let (topic, due) = match self.due.iter().min_by_key(|(_, due)| **due) {
+            Some((topic, due)) => (topic.clone(), *due),
+            None => return std::future::pending().await,
+        };
+
+        tokio::time::sleep_until(due.into()).await;
+        self.due.remove(&topic);
+        topic
+    }
+}
@@ -0,0 +1,76 @@
+use anyhow::Result;
+use log::{debug, trace};
+use std::fmt::Debug;
+use std::io::ErrorKind;
+use std::panic::Location;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Instant;
+
+use crate::error::BridgeError;
+
+/// Number of times a device request is retried when the device doesn't answer in time. Set
+/// from the config on startup.
+static RETRIES: AtomicU32 = AtomicU32::new(0);
+
+pub fn set_retries(retries: u32) {
+    RETRIES.store(retries, Ordering::Relaxed);
+}
+
+/// Whether every device request is logged with its duration and result. `uhppote-rs` doesn't
+/// expose the packets it sends and receives, so this is as close to them as it gets.
+static TRACE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_trace(trace: bool) {
+    TRACE.store(trace, Ordering::Relaxed);
+}
+
+/// Send a device request with `f`, and send it again when it times out. The devices talk UDP,
+/// so a packet lost on a busy network shouldn't fail the request right away. Errors are
+/// returned as [`BridgeError::Device`].
+#[track_caller]
+pub fn retry<T: Debug>(mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let caller = Location::caller();
+    let retries = RETRIES.load(Ordering::Relaxed);
+    let mut attempt = 0;
+    loop {
+        let start = Instant::now();
+        let result = f();
+        if TRACE.load(Ordering::Relaxed) {
+            let outcome = match &result {
+                Ok(response) => format!("{:?}", response),
+                Err(e) => format!("failed: {:#}", e),
+            };
+            // The call site tells which request it was
+            trace!(
+                "Device request at {}:{} took {:?}: {}",
+                caller.file(),
+                caller.line(),
+                start.elapsed(),
+                outcome
+            );
+        }
+        let e = match result {
+            Ok(result) => return Ok(result),
+            Err(e) => BridgeError::Device(e),
+        };
+        if attempt >= retries || !e.is_retryable() {
+            return Err(e.into());
+        }
+        attempt += 1;
+        debug!(
+            "Device request timed out, retrying ({}/{})",
+            attempt, retries
+        );
+    }
+}
+
+/// Whether `e` is a device request that timed out.
+pub fn is_timeout(e: &anyhow::Error) -> bool {
+    // A wrapped error isn't a source of its own, so it's only reachable through the variant
+    e.chain().any(|e| match e.downcast_ref::<BridgeError>() {
+        Some(BridgeError::Device(e)) => is_timeout(e),
+        _ => e
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)),
+    })
+}
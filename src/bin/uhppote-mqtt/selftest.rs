@@ -0,0 +1,112 @@
+use anyhow::Result;
+use std::fmt::Display;
+use std::time::Duration;
+use uhppote_rs::{Device, Uhppoted};
+
+use crate::commands::{self, State};
+use crate::config::{Config, DoorConfig};
+use crate::locate;
+use crate::retry::retry;
+
+/// Time a door stays in each state of the lock cycle, so the relay can be heard or watched.
+const CYCLE_DELAY: Duration = Duration::from_secs(3);
+
+/// Check the communication with every configured device and print a report, for commissioning
+/// a new install. Reads the status, time and door states and, with `cycle`, locks, unlocks and
+/// locks every door again. Returns whether all steps passed.
+pub fn run(config: &Config, cycle: bool) -> bool {
+    let mut passed = true;
+    for device_config in &config.devices {
+        let id = device_config.uhppote_device_id;
+        println!("Device {}", id);
+        let uhppoted = Uhppoted::new(
+            device_config.bind_address.unwrap_or(config.bind_address),
+            config.broadcast_address,
+            Duration::from_secs(config.timeout_seconds),
+        );
+        // Locating a device takes a Uhppoted that lives as long as the process, like on startup
+        let uhppoted: &'static Uhppoted = Box::leak(Box::new(uhppoted));
+
+        let location = locate::locate(uhppoted, id, device_config.device_ip());
+        let address = location
+            .ip
+            .map_or("broadcast".to_string(), |ip| ip.to_string());
+        let name = format!("Connect at {}", address);
+        if !location.reachable {
+            passed &= report(&name, false, "no response");
+            continue;
+        }
+        report(&name, true, "responds");
+        let device = uhppoted.get_device(id, location.ip);
+
+        passed &= step("Read status", retry(|| device.get_status()).map(|_| "ok"));
+        passed &= step("Read time", retry(|| device.get_time()));
+
+        for door in &device_config.doors {
+            let state = retry(|| device.get_door_control(door.door)).map(|control| {
+                let state = State::from_mode(&control.mode)
+                    .map_or("unknown", |state| config.state_name(state));
+                format!("{}, delay {}s", state, control.delay.as_secs())
+            });
+            passed &= step(
+                &format!("Read state of door {} '{}'", door.door, door.name),
+                state,
+            );
+        }
+
+        if cycle {
+            for door in &device_config.doors {
+                let actions = [("lock", "Lock"), ("unlock", "Unlock"), ("lock", "Lock")];
+                for (i, (action, name)) in actions.iter().enumerate() {
+                    if i > 0 {
+                        std::thread::sleep(CYCLE_DELAY);
+                    }
+                    let result = send(config, &device, door, action);
+                    passed &= step(
+                        &format!("{} door {} '{}'", name, door.door, door.name),
+                        result,
+                    );
+                }
+            }
+        }
+    }
+
+    if passed {
+        println!("All checks passed");
+    } else {
+        println!("Some checks failed");
+    }
+    passed
+}
+
+/// Send `action` to `door` like a command from MQTT, and describe the state it ends up in.
+fn send(config: &Config, device: &Device, door: &DoorConfig, action: &str) -> Result<String> {
+    let payload = format!("{{\"action\":\"{}\"}}", action);
+    let delay = Duration::from_secs(config.open_delay.into());
+    let transition = commands::handle_payload(
+        device,
+        door,
+        delay,
+        config.enforce_delay,
+        payload.as_bytes(),
+        &config.aliases,
+        false,
+    )?;
+    Ok(transition.map_or("done".to_string(), |transition| {
+        config.state_name(transition.state).to_string()
+    }))
+}
+
+/// Print the outcome of `result` for the step `name`, and return whether it passed.
+fn step<T: Display>(name: &str, result: Result<T>) -> bool {
+    match result {
+        Ok(detail) => report(name, true, detail),
+        Err(e) => report(name, false, format!("{:#}", e)),
+    }
+}
+
+fn report(name: &str, passed: bool, detail: impl Display) -> bool {
+    let outcome = if passed { "PASS" } else { "FAIL" };
+    println!("  {}  {}: {}", outcome, name, detail);
+    passed
+}
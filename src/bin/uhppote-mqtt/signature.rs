@@ -0,0 +1,78 @@
+use anyhow::{anyhow, bail, Context, Result};
+use ring::hmac;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Signed message. `sig` is the hex encoded HMAC-SHA256 of the topic, `ts` and `cmd`, each
+/// followed by a newline but the last.
+#[derive(Deserialize)]
+struct Envelope {
+    cmd: String,
+    // Unix time in milliseconds
+    ts: u64,
+    sig: String,
+}
+
+/// Checks the signatures of incoming messages, so only publishers that know the key can send
+/// commands. A signed message is only accepted close to when it was signed, and only once.
+pub struct Verifier {
+    key: hmac::Key,
+    max_age: Duration,
+    // Timestamp of the last accepted message by topic
+    last: HashMap<String, u64>,
+}
+
+impl Verifier {
+    pub fn new(key: &str, max_age: Duration) -> Self {
+        Verifier {
+            key: hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes()),
+            max_age,
+            last: HashMap::new(),
+        }
+    }
+
+    /// The command in the signed `payload` received on `topic`. Fails when the payload isn't
+    /// signed with the key, is too old or was accepted before.
+    pub fn verify(&mut self, topic: &str, payload: &[u8]) -> Result<String> {
+        let envelope: Envelope = serde_json::from_slice(payload).context("Not a signed message")?;
+        let sig = decode_hex(&envelope.sig).ok_or_else(|| anyhow!("Invalid signature"))?;
+        let message = format!("{}\n{}\n{}", topic, envelope.ts, envelope.cmd);
+        hmac::verify(&self.key, message.as_bytes(), &sig)
+            .map_err(|_| anyhow!("Invalid signature"))?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        if now.abs_diff(envelope.ts) > self.max_age.as_millis() as u64 {
+            bail!(
+                "Signed at {}, more than {}s from the time of the bridge",
+                envelope.ts,
+                self.max_age.as_secs()
+            );
+        }
+        // A message has to be newer than the last one, which also rejects replays
+        if let Some(last) = self.last.get(topic) {
+            if envelope.ts <= *last {
+                bail!(
+                    "Signed at {}, not after the last accepted message",
+                    envelope.ts
+                );
+            }
+        }
+
+        self.last.insert(topic.to_string(), envelope.ts);
+        Ok(envelope.cmd)
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,319 @@
+use log::{debug, error, info, warn};
+use rumqttc::AsyncClient;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::audit;
+use crate::commands::State;
+use crate::config::{Config, DeviceConfig, DoorConfig, StateFormat};
+use crate::locate::DeviceHandle;
+use crate::metrics;
+use crate::open_time;
+use crate::retry::retry;
+use crate::template::{self, StateContext};
+
+/// State of a door and when it last changed, by state topic. Used for `changed_at` in the JSON
+/// state format.
+static CHANGES: Mutex<BTreeMap<String, (State, String)>> = Mutex::new(BTreeMap::new());
+
+/// Payload last published to each state topic, and when. Used to suppress duplicate states.
+static PUBLISHED: Mutex<BTreeMap<String, (String, Instant)>> = Mutex::new(BTreeMap::new());
+
+/// State as published to the state topic with the JSON state format.
+#[derive(Serialize)]
+struct StatePayload<'a> {
+    state: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delay: Option<u64>,
+    changed_at: String,
+}
+
+/// Payload to publish `state` to `topic` with, in the configured format or from the state
+/// template, and with the configured state strings. `delay` is the door's open delay, if known.
+pub fn payload(config: &Config, topic: &str, state: State, delay: Option<Duration>) -> String {
+    if let Some(template) = &config.state_template {
+        if let Some((device, door)) = find_door(config, topic) {
+            let context = StateContext {
+                state: config.state_name(state),
+                mode: state.mode(),
+                delay: delay.map(|d| d.as_secs()),
+                changed_at: &changed_at(topic, state),
+                door: door.door,
+                name: &door.name,
+                device_id: device.uhppote_device_id,
+            };
+            match template::render(template, &context) {
+                Ok(payload) => return payload,
+                Err(e) => error!("Failed to render 'state_template' for {}: {}", topic, e),
+            }
+        }
+    }
+
+    match config.state_format {
+        StateFormat::Plain => config.state_name(state).to_string(),
+        StateFormat::Json => serde_json::to_string(&StatePayload {
+            state: config.state_name(state),
+            mode: state.mode(),
+            delay: delay.map(|d| d.as_secs()),
+            changed_at: changed_at(topic, state),
+        })
+        .unwrap(),
+    }
+}
+
+/// When the door with the state topic `topic` changed to `state`, which is now if it wasn't in
+/// that state before.
+fn changed_at(topic: &str, state: State) -> String {
+    let mut changes = CHANGES.lock().unwrap();
+    match changes.get(topic) {
+        Some((last, changed_at)) if *last == state => changed_at.clone(),
+        _ => {
+            let now = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+            changes.insert(topic.to_string(), (state, now.clone()));
+            now
+        }
+    }
+}
+
+/// Door with the state topic `topic`, and its device.
+fn find_door<'a>(config: &'a Config, topic: &str) -> Option<(&'a DeviceConfig, &'a DoorConfig)> {
+    config.devices.iter().find_map(|device| {
+        device
+            .doors
+            .iter()
+            .find(|door| door.state_topic() == topic)
+            .map(|door| (device, door))
+    })
+}
+
+/// Whether `payload` should be published to the state topic `topic`. With
+/// `suppress_duplicate_states`, a payload identical to the last one published to the topic is
+/// skipped, until `force_publish_interval` has passed since it was published.
+pub fn should_publish(config: &Config, topic: &str, payload: &str) -> bool {
+    if !config.suppress_duplicate_states {
+        return true;
+    }
+    let now = Instant::now();
+    let mut published = PUBLISHED.lock().unwrap();
+    if let Some((last, at)) = published.get(topic) {
+        let force = config.force_publish_interval > 0
+            && now >= *at + Duration::from_secs(config.force_publish_interval);
+        if last == payload && !force {
+            debug!("Not publishing {} to {}, it's unchanged", payload, topic);
+            return false;
+        }
+    }
+    published.insert(topic.to_string(), (payload.to_string(), now));
+    true
+}
+
+/// Forget what was published to the state topics, so every state is published again. The broker
+/// may have lost them when the connection dropped.
+pub fn forget_published() {
+    PUBLISHED.lock().unwrap().clear();
+}
+
+/// State and delay a door was last set to.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct SavedDoor {
+    state: State,
+    delay: u64,
+}
+
+/// Last commanded state of every door by state topic, persisted so it can be published right
+/// after a restart.
+pub struct StateFile {
+    path: PathBuf,
+    doors: HashMap<String, SavedDoor>,
+}
+
+impl StateFile {
+    /// Read the saved states from `path`. A missing or corrupt file is treated as empty.
+    pub fn load(path: PathBuf) -> Self {
+        let doors = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                warn!("Ignoring invalid state file {:?}: {}", path, e);
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                warn!("Failed to read state file {:?}: {}", path, e);
+                HashMap::new()
+            }
+        };
+
+        StateFile { path, doors }
+    }
+
+    /// Saved state of every door by state topic.
+    pub fn states(&self) -> HashMap<String, State> {
+        self.doors
+            .iter()
+            .map(|(topic, door)| (topic.clone(), door.state))
+            .collect()
+    }
+
+    /// Save the state of the door with `state_topic`. The file is replaced atomically, so a
+    /// crash while writing doesn't corrupt it.
+    pub fn set(&mut self, state_topic: &str, state: State, delay: u64) {
+        self.doors
+            .insert(state_topic.to_string(), SavedDoor { state, delay });
+
+        let tmp = self.path.with_extension("tmp");
+        let contents = serde_json::to_string(&self.doors).unwrap();
+        if let Err(e) =
+            std::fs::write(&tmp, contents).and_then(|_| std::fs::rename(&tmp, &self.path))
+        {
+            error!("Failed to write state file {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Hash of every discovery config by topic, kept next to the state file. The broker retains the
+/// configs, so they're only published again when they change, or when Home Assistant asks for
+/// them by coming online.
+pub struct DiscoveryVersions {
+    path: Option<PathBuf>,
+    // Hash of the last published config by topic, starting with those of the previous run
+    published: BTreeMap<String, String>,
+    // Topics that got a config in the current round of discovery
+    seen: BTreeSet<String>,
+    // Publish every config in the current round, changed or not
+    force: bool,
+}
+
+impl DiscoveryVersions {
+    /// Read the hashes of the previous run from next to `state_file`. Without a state file,
+    /// all configs are published on startup.
+    pub fn load(state_file: Option<&Path>) -> Self {
+        let path = state_file.map(|path| path.with_extension("discovery"));
+        let published = match &path {
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                    warn!("Ignoring invalid discovery versions {:?}: {}", path, e);
+                    BTreeMap::new()
+                }),
+                Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+                Err(e) => {
+                    warn!("Failed to read discovery versions {:?}: {}", path, e);
+                    BTreeMap::new()
+                }
+            },
+            None => BTreeMap::new(),
+        };
+
+        DiscoveryVersions {
+            path,
+            published,
+            seen: BTreeSet::new(),
+            force: false,
+        }
+    }
+
+    /// Publish every config in the next round of discovery, like when Home Assistant restarted.
+    pub fn force(&mut self) {
+        self.force = true;
+    }
+
+    /// Record `payload` as the config for `topic`. Returns whether it needs to be published,
+    /// which is when it differs from the last published one.
+    pub fn update(&mut self, topic: &str, payload: &str) -> bool {
+        let hash = format!("{:016x}", fnv1a(payload.as_bytes()));
+        self.seen.insert(topic.to_string());
+        let previous = self.published.insert(topic.to_string(), hash.clone());
+        self.force || previous != Some(hash)
+    }
+
+    /// End a round of discovery. Saves the hashes and returns the topics that had a config
+    /// before, but not in this round.
+    pub fn finish(&mut self) -> Vec<String> {
+        let seen = std::mem::take(&mut self.seen);
+        self.force = false;
+        let stale = self
+            .published
+            .keys()
+            .filter(|topic| !seen.contains(*topic))
+            .cloned()
+            .collect::<Vec<_>>();
+        for topic in &stale {
+            self.published.remove(topic);
+        }
+
+        if let Some(path) = &self.path {
+            let tmp = path.with_extension("discovery.tmp");
+            let contents = serde_json::to_string(&self.published).unwrap();
+            if let Err(e) = std::fs::write(&tmp, contents).and_then(|_| std::fs::rename(&tmp, path))
+            {
+                error!("Failed to write discovery versions {:?}: {}", path, e);
+            }
+        }
+        stale
+    }
+}
+
+/// 64-bit FNV-1a, which unlike the standard library hasher is stable between releases.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Publish the saved state of every door. Doors without a saved state are read from the device
+/// instead. `devices` are in the same order as `config.devices`.
+pub async fn restore(
+    client: AsyncClient,
+    config: Arc<Config>,
+    devices: Vec<Arc<DeviceHandle>>,
+    saved: HashMap<String, State>,
+) {
+    for (device_config, device) in config.devices.iter().zip(&devices) {
+        for door in &device_config.doors {
+            let state_topic = door.state_topic();
+            let (state, delay) = match saved.get(&state_topic) {
+                Some(state) => {
+                    info!("Restoring {} to {}", state.as_str(), &state_topic);
+                    (*state, None)
+                }
+                None => {
+                    let device = device.clone();
+                    let number = door.door;
+                    let result = tokio::task::spawn_blocking(move || {
+                        retry(|| device.get_door_control(number))
+                    })
+                    .await
+                    .unwrap();
+                    match result.map(|control| (State::from_mode(&control.mode), control.delay)) {
+                        Ok((Some(state), delay)) => (state, Some(delay)),
+                        Ok((None, _)) => {
+                            warn!("Door {} is in an unknown mode", number);
+                            continue;
+                        }
+                        Err(e) => {
+                            metrics::DEVICE_ERRORS.inc();
+                            error!("Failed to get state of door {}: {}", number, e);
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            let payload = payload(&config, &state_topic, state, delay);
+            if !should_publish(&config, &state_topic, &payload) {
+                continue;
+            }
+            debug!("Publishing {} to {}", &payload, &state_topic);
+            audit::state(&state_topic, &payload);
+            open_time::state(&state_topic, state);
+            client
+                .publish(&state_topic, config.state_qos, config.retain_state, payload)
+                .await
+                .unwrap();
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use anyhow::Result;
+use serde::Serialize;
+use uhppote_rs::Device;
+
+use crate::events::EventPayload;
+use crate::metrics;
+use crate::retry::retry;
+
+/// Complete status of a controller as published to the status topic.
+#[derive(Serialize)]
+struct StatusPayload {
+    device_id: u32,
+    // Local time of the controller, like `2024-01-31T12:00:00`
+    controller_time: String,
+    // All four doors, whether configured or not
+    doors: Vec<DoorStatus>,
+    // Raw bitmask of the inputs
+    input_state: u8,
+    system_error: u8,
+    special_info: u8,
+    sequence_number: u32,
+    last_event: Option<EventPayload>,
+}
+
+#[derive(Serialize)]
+struct DoorStatus {
+    door: u8,
+    // Whether the door sensor reports the door as open
+    open: bool,
+    button_pressed: bool,
+    // Whether the lock relay is energized
+    relay_active: bool,
+}
+
+/// Read the status of `device` and return the payload for the status topic.
+pub fn handle(device: &Device) -> String {
+    match status(device) {
+        Ok(status) => serde_json::to_string(&status).unwrap(),
+        Err(e) => {
+            metrics::DEVICE_ERRORS.inc();
+            serde_json::json!({ "error": e.to_string() }).to_string()
+        }
+    }
+}
+
+fn status(device: &Device) -> Result<StatusPayload> {
+    let status = retry(|| device.get_status())?;
+    let doors = (1..=4)
+        .zip(status.doors.iter().zip(&status.buttons))
+        .map(|(door, (open, button))| DoorStatus {
+            door,
+            open: *open,
+            button_pressed: *button,
+            relay_active: status.relay_state & (1 << (door - 1)) != 0,
+        })
+        .collect();
+
+    Ok(StatusPayload {
+        device_id: status.device_id,
+        controller_time: status
+            .system_date
+            .and_time(status.system_time)
+            .format("%Y-%m-%dT%H:%M:%S")
+            .to_string(),
+        doors,
+        input_state: status.input_state,
+        system_error: status.system_error,
+        special_info: status.special_info,
+        sequence_number: status.sequence_number,
+        last_event: status.last_event.as_ref().map(EventPayload::from),
+    })
+}
@@ -0,0 +1,143 @@
+use chrono::{SecondsFormat, Utc};
+use log::{error, info};
+use std::sync::OnceLock;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use uhppote_rs::Event;
+
+use crate::commands::CommandResult;
+use crate::config::{self, DoorConfig};
+
+/// Messages for the syslog server. Sending is left to a separate task, so a slow or
+/// unreachable server doesn't hold up the MQTT loop.
+static SYSLOG: OnceLock<UnboundedSender<String>> = OnceLock::new();
+
+/// Security and authorization messages
+const FACILITY_AUTHPRIV: u8 = 10;
+const SEVERITY_WARNING: u8 = 4;
+const SEVERITY_NOTICE: u8 = 5;
+const SEVERITY_INFO: u8 = 6;
+
+/// SD-ID of the structured data. 32473 is the private enterprise number reserved for
+/// documentation and examples.
+const SD_ID: &str = "uhppote@32473";
+
+/// Start sending access events and commands to the syslog server at `target`, as `host:port`
+/// over UDP.
+pub fn start(target: String) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    SYSLOG.set(tx).expect("Syslog already started");
+    info!("Sending events and commands to syslog server {}", target);
+    tokio::spawn(send(target, rx));
+}
+
+/// Send a command for `door` of the device with `device_id` received from `source` and its
+/// outcome, if it wasn't ignored.
+pub fn command(
+    source: &'static str,
+    device_id: u32,
+    door: &DoorConfig,
+    result: Option<&CommandResult>,
+) {
+    let result = match result {
+        Some(result) => result,
+        None => return,
+    };
+    let severity = if result.success {
+        SEVERITY_NOTICE
+    } else {
+        SEVERITY_WARNING
+    };
+    let mut message = format!(
+        "Command {} for {} from {}",
+        result.command, door.name, source
+    );
+    match &result.error {
+        Some(e) => message.push_str(&format!(" failed: {}", e)),
+        None => message.push_str(" succeeded"),
+    }
+    record(
+        severity,
+        "command",
+        &[
+            ("device", device_id.to_string()),
+            ("door", door.door.to_string()),
+            ("source", source.to_string()),
+            ("success", result.success.to_string()),
+        ],
+        &message,
+    );
+}
+
+/// Send an access `event` of the device with `device_id`, with the `payload` it was published
+/// with.
+pub fn event(device_id: u32, event: &Event, payload: &str) {
+    // Door 0 is the controller itself, like for a restart
+    let severity = match (event.door, event.granted) {
+        (0, _) => SEVERITY_INFO,
+        (_, true) => SEVERITY_NOTICE,
+        (_, false) => SEVERITY_WARNING,
+    };
+    record(
+        severity,
+        "event",
+        &[
+            ("device", device_id.to_string()),
+            ("door", event.door.to_string()),
+            ("card", event.card_number.to_string()),
+            ("granted", event.granted.to_string()),
+        ],
+        payload,
+    );
+}
+
+/// Format a message in the RFC 5424 syslog format and queue it.
+fn record(severity: u8, msg_id: &str, data: &[(&str, String)], message: &str) {
+    let syslog = match SYSLOG.get() {
+        Some(syslog) => syslog,
+        None => return,
+    };
+    let params: String = data
+        .iter()
+        .map(|(name, value)| format!(" {}=\"{}\"", name, escape(value)))
+        .collect();
+    syslog
+        .send(format!(
+            "<{}>1 {} {} uhppote-mqtt {} {} [{}{}] {}",
+            FACILITY_AUTHPRIV * 8 + severity,
+            Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            config::hostname(),
+            std::process::id(),
+            msg_id,
+            SD_ID,
+            params,
+            message
+        ))
+        .ok();
+}
+
+/// Escape `value` for a structured data parameter, which must not contain a bare `"`, `\` or
+/// `]`.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(']', "\\]")
+}
+
+/// Send the messages from `rx` to `target` until the channel closes.
+async fn send(target: String, mut rx: UnboundedReceiver<String>) {
+    let socket = match UdpSocket::bind("0.0.0.0:0").await {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Failed to bind syslog socket: {}", e);
+            return;
+        }
+    };
+    while let Some(message) = rx.recv().await {
+        // The target is resolved for every message, so it follows DNS changes
+        if let Err(e) = socket.send_to(message.as_bytes(), target.as_str()).await {
+            error!("Failed to send to syslog server {}: {}", target, e);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use tinytemplate::{format_unescaped, TinyTemplate};
+
+use crate::config::DoorConfig;
+
+/// Stands in for the command when rendering the command template, to find where it goes.
+const COMMAND_MARKER: &str = "\u{0}";
+
+/// What the state template can refer to.
+#[derive(Serialize)]
+pub struct StateContext<'a> {
+    pub state: &'a str,
+    pub mode: Option<&'static str>,
+    pub delay: Option<u64>,
+    pub changed_at: &'a str,
+    pub door: u8,
+    pub name: &'a str,
+    pub device_id: u32,
+}
+
+/// What the command template can refer to.
+#[derive(Serialize)]
+struct CommandContext<'a> {
+    command: &'a str,
+    door: u8,
+    name: &'a str,
+    device_id: u32,
+}
+
+/// Render `template` with the fields of `context`. Values are inserted as they are, without the
+/// HTML escaping TinyTemplate does by default.
+pub fn render<C: Serialize>(template: &str, context: &C) -> Result<String> {
+    let mut tt = TinyTemplate::new();
+    tt.set_default_formatter(&format_unescaped);
+    tt.add_template("template", template)?;
+    Ok(tt.render("template", context)?)
+}
+
+/// Check that `template` renders, for the config validation.
+pub fn check_state(template: &str) -> Result<()> {
+    let context = StateContext {
+        state: "LOCKED",
+        mode: Some("controlled"),
+        delay: Some(5),
+        changed_at: "2022-01-01T00:00:00Z",
+        door: 1,
+        name: "Door",
+        device_id: 1,
+    };
+    render(template, &context)
+        .map(|_| ())
+        .context("Invalid 'state_template'")
+}
+
+/// Check that `template` renders and has the command in it once, for the config validation.
+pub fn check_command(template: &str) -> Result<()> {
+    let context = CommandContext {
+        command: COMMAND_MARKER,
+        door: 1,
+        name: "Door",
+        device_id: 1,
+    };
+    let rendered = render(template, &context).context("Invalid 'command_template'")?;
+    if rendered.matches(COMMAND_MARKER).count() != 1 {
+        bail!("'command_template' must contain {{command}} exactly once");
+    }
+    Ok(())
+}
+
+/// Command in `payload` for `door` of the device with `device_id`, according to `template`. The
+/// payload has to match the template literally, apart from the command and surrounding
+/// whitespace. Returns `None` when it doesn't.
+pub fn command<'a>(
+    template: &str,
+    device_id: u32,
+    door: &DoorConfig,
+    payload: &'a str,
+) -> Option<&'a str> {
+    let rendered = payload_for(template, device_id, door, COMMAND_MARKER);
+    let (prefix, suffix) = rendered.split_once(COMMAND_MARKER).unwrap();
+    payload
+        .trim()
+        .strip_prefix(prefix.trim_start())?
+        .strip_suffix(suffix.trim_end())
+}
+
+/// Payload that sends `command` to `door` of the device with `device_id` according to
+/// `template`, like for the discovery configs.
+pub fn payload_for(template: &str, device_id: u32, door: &DoorConfig, command: &str) -> String {
+    let context = CommandContext {
+        command,
+        door: door.door,
+        name: &door.name,
+        device_id,
+    };
+    // The template is checked on startup
+    render(template, &context).unwrap()
+}
@@ -0,0 +1,96 @@
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use uhppote_rs::Event;
+
+use crate::metrics;
+
+/// Protects the broker and automations from event storms, like when someone leans on a reader.
+/// An event that's identical to the last published event of its device is suppressed within the
+/// dedup window, and events beyond the rate limit are dropped. The first event always goes
+/// through.
+pub struct EventThrottle {
+    dedup_window: Duration,
+    // Events per second, 0 for no limit
+    rate_limit: u32,
+    // Last published event by device id, and when it was published
+    last: HashMap<u32, (String, Instant)>,
+    // Start of the current one second window, and the events published and dropped in it
+    window_start: Instant,
+    published: u32,
+    dropped: u32,
+}
+
+impl EventThrottle {
+    pub fn new(dedup_window: Duration, rate_limit: u32) -> Self {
+        EventThrottle {
+            dedup_window,
+            rate_limit,
+            last: HashMap::new(),
+            window_start: Instant::now(),
+            published: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Whether `event` of the device with `device_id` should be published.
+    pub fn allow(&mut self, device_id: u32, event: &Event) -> bool {
+        let now = Instant::now();
+        let key = key(event);
+        if let Some((last, at)) = self.last.get(&device_id) {
+            if *last == key && now < *at + self.dedup_window {
+                debug!(
+                    "Suppressing event {} of device {}, it's identical to the previous one",
+                    event.index, device_id
+                );
+                return false;
+            }
+        }
+
+        if self.rate_limit > 0 {
+            if now >= self.window_start + Duration::from_secs(1) {
+                if self.dropped > 0 {
+                    warn!(
+                        "Dropped {} events over the limit of {} per second",
+                        self.dropped, self.rate_limit
+                    );
+                }
+                self.window_start = now;
+                self.published = 0;
+                self.dropped = 0;
+            }
+            if self.published >= self.rate_limit {
+                if self.dropped == 0 {
+                    warn!(
+                        "Received more than {} events in a second, dropping events",
+                        self.rate_limit
+                    );
+                }
+                self.dropped += 1;
+                metrics::EVENTS_DROPPED.inc();
+                return false;
+            }
+            self.published += 1;
+        }
+
+        if !self.dedup_window.is_zero() {
+            self.last.insert(device_id, (key, now));
+        }
+        true
+    }
+}
+
+/// What makes two events identical, which is everything but their index and timestamp.
+fn key(event: &Event) -> String {
+    format!(
+        "{:?}",
+        (
+            &event.event_type,
+            event.door,
+            &event.direction,
+            event.card_number,
+            event.granted,
+            &event.reason,
+        )
+    )
+}